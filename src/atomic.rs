@@ -0,0 +1,58 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Write to a temporary file which is flushed to disk before being
+/// renamed over the original so readers only see a complete file.
+pub fn write(file: &str, data: &[u8]) -> io::Result<()> {
+    write_mode(file, data, 0o666)
+}
+
+/// Write as above to a file only readable by its owner, for secrets.
+pub fn write_private(file: &str, data: &[u8]) -> io::Result<()> {
+    write_mode(file, data, 0o600)
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_mode(file: &str, data: &[u8], mode: u32) -> io::Result<()> {
+    let tmp = format!("{}.tmp", file);
+    // A temporary file left behind by a crash may have other permissions
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    let mut f = options.open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, file)?;
+    // Flush the directory as well so the rename itself survives a crash
+    let dir = match Path::new(file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_write_private() {
+        let file = format!("{}/d2l-sync-token-{}", ::std::env::temp_dir().display(), ::std::process::id());
+        write(&file, b"old").unwrap();
+        write_private(&file, b"new").unwrap();
+        assert_eq!("new", fs::read_to_string(&file).unwrap());
+        assert_eq!(0o600, fs::metadata(&file).unwrap().permissions().mode() & 0o777);
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use base64::URL_SAFE_NO_PAD;
+
+use hyper::StatusCode;
+use reqwest::{Client, Method, RequestBuilder};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use atomic;
+use sync::SyncError;
+use log::{self, Fields};
+use redact::Secret;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authenticates every request sent to d2l.
+pub trait Authenticator: fmt::Debug + Send + ::std::marker::Sync {
+    /// Build an authenticated request for the path. Any additional
+    /// query arguments must be prefixed with an '&'.
+    fn request(&self, client: &Client, method: Method, uri_base: &str, path: &str, query: &str) -> Result<RequestBuilder, SyncError>;
+
+    /// Given a rejected request's status and body decide whether the
+    /// request should be authenticated and sent once more.
+    fn recover(&self, client: &Client, status: StatusCode, body: &str) -> Result<bool, SyncError>;
+}
+
+/// D2L ID/key authentication which signs the method, path, and
+/// timestamp of each request with both the app and user keys.
+pub struct IdKey {
+    pub app_id: String,
+    pub app_key: Vec<u8>,
+    pub usr_id: String,
+    pub usr_key: Vec<u8>,
+    /// Seconds the d2l server clock is ahead of ours.
+    pub skew: AtomicI64,
+}
+
+// The keys, and the user id they belong to, never appear in Debug output
+impl fmt::Debug for IdKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdKey")
+            .field("app_id", &self.app_id)
+            .field("app_key", &Secret)
+            .field("usr_id", &Secret)
+            .field("usr_key", &Secret)
+            .field("skew", &self.skew)
+            .finish()
+    }
+}
+
+impl Authenticator for IdKey {
+    fn request(&self, client: &Client, method: Method, uri_base: &str, path: &str, query: &str) -> Result<RequestBuilder, SyncError> {
+        let epoch = Utc::now().timestamp() + self.skew.load(Ordering::Relaxed);
+        let sig_body = sig_body(&method, path, epoch);
+
+        let app_sig = signature(&self.app_key, sig_body.as_bytes());
+        let usr_sig = signature(&self.usr_key, sig_body.as_bytes());
+
+        let uri = format!("{}{}?{}&x_a={}&x_c={}&x_b={}&x_d={}&x_t={}", uri_base, path, query, self.app_id, app_sig, self.usr_id, usr_sig, epoch);
+        Ok(client.request(method, &uri))
+    }
+
+    // A drifting clock is rejected by d2l along with the server time
+    // which we use to correct the skew before signing again.
+    fn recover(&self, _client: &Client, status: StatusCode, body: &str) -> Result<bool, SyncError> {
+        if status != StatusCode::FORBIDDEN {
+            return Ok(false);
+        }
+        match timestamp_out_of_range(body) {
+            Some(server) => {
+                let skew = server - Utc::now().timestamp();
+                self.skew.store(skew, Ordering::Relaxed);
+                log::warn(&format!("Timestamp out of range, correcting clock skew by {}s", skew), Fields::default());
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+// Parse the server time from a 403 "Timestamp out of range" response body.
+fn timestamp_out_of_range(body: &str) -> Option<i64> {
+    if !body.contains("Timestamp out of range") {
+        return None;
+    }
+    body.split(|c: char| !c.is_ascii_digit()).rfind(|s| !s.is_empty())?.parse::<i64>().ok()
+}
+
+// D2L validates the signature against the lower cased path, which
+// differs from the path sent for routes such as /enrollments/orgUnits/.
+fn sig_body(method: &Method, path: &str, epoch: i64) -> String {
+    format!("{}&{}&{}", method, path.to_lowercase(), epoch)
+}
+
+fn signature(key: &[u8], message: &[u8]) -> String {
+    let mut mac = HmacSha256::new_varkey(key).unwrap();
+    mac.input(message);
+    base64::encode_config(&mac.result().code(), URL_SAFE_NO_PAD)
+}
+
+/// OAuth 2.0 bearer token authentication using the refresh token grant.
+/// D2L rotates the refresh token on every use so the latest one is kept
+/// within the refresh token file.
+pub struct OAuth2 {
+    pub client_id: String,
+    pub client_secret: String,
+    pub token_uri: String,
+    pub refresh_token_file: String,
+    pub token: Mutex<Option<Token>>,
+}
+
+#[derive(Clone)]
+pub struct Token {
+    access_token: String,
+    expires: i64,
+}
+
+impl fmt::Debug for OAuth2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OAuth2")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &Secret)
+            .field("token_uri", &self.token_uri)
+            .field("refresh_token_file", &self.refresh_token_file)
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("access_token", &Secret)
+            .field("expires", &self.expires)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+    refresh_token: Option<String>,
+}
+
+impl OAuth2 {
+    // Returns the cached access token refreshing it shortly before it expires.
+    fn access_token(&self, client: &Client) -> Result<String, SyncError> {
+        let mut token = self.token.lock().unwrap();
+        if let Some(ref token) = *token {
+            if token.expires > Utc::now().timestamp() + 60 {
+                return Ok(token.access_token.clone());
+            }
+        }
+        let refreshed = self.refresh(client)?;
+        let access_token = refreshed.access_token.clone();
+        *token = Some(refreshed);
+        Ok(access_token)
+    }
+
+    fn refresh(&self, client: &Client) -> Result<Token, SyncError> {
+        let mut refresh_token = String::new();
+        File::open(&self.refresh_token_file)?.read_to_string(&mut refresh_token)?;
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.trim()),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+        ];
+        let mut resp = client.post(&self.token_uri).form(&params).send()?;
+        if resp.status() != StatusCode::OK {
+            return Err(SyncError::StatusCode(resp.status()));
+        }
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        let token: TokenResponse = serde_json::from_str(&body)?;
+        if let Some(refresh_token) = token.refresh_token {
+            // Losing the rotated token would require a new one to be issued by hand
+            atomic::write_private(&self.refresh_token_file, refresh_token.as_bytes())?;
+        }
+        Ok(Token {
+            access_token: token.access_token,
+            expires: Utc::now().timestamp() + token.expires_in,
+        })
+    }
+}
+
+impl Authenticator for OAuth2 {
+    fn request(&self, client: &Client, method: Method, uri_base: &str, path: &str, query: &str) -> Result<RequestBuilder, SyncError> {
+        let access_token = self.access_token(client)?;
+        let uri = format!("{}{}?{}", uri_base, path, query);
+        Ok(client.request(method, &uri).bearer_auth(access_token))
+    }
+
+    // An expired or revoked access token is dropped so the
+    // next request will refresh it.
+    fn recover(&self, _client: &Client, status: StatusCode, _body: &str) -> Result<bool, SyncError> {
+        if status != StatusCode::UNAUTHORIZED {
+            return Ok(false);
+        }
+        log::warn("Access token rejected, refreshing", Fields::default());
+        *self.token.lock().unwrap() = None;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_out_of_range() {
+        assert_eq!(Some(1539872461), timestamp_out_of_range("Timestamp out of range\r\n1539872461"));
+        assert_eq!(Some(1539872461), timestamp_out_of_range("Timestamp out of range 1539872461\n"));
+        assert_eq!(None, timestamp_out_of_range("Invalid token"));
+        assert_eq!(None, timestamp_out_of_range("Timestamp out of range"));
+    }
+
+    #[test]
+    fn test_signature() {
+        assert_eq!("E_75C-OQsfFBTL2NEEJ9qYZ5cKR6mT3zjH1fiInqjCM", signature(b"key", b"GET&/d2l/api/lp/1.20/users/&1539872461"));
+        let sig_body = sig_body(&Method::POST, "/d2l/api/lp/1.43/enrollments/orgUnits/6606/users/", 1539872461);
+        assert_eq!("POST&/d2l/api/lp/1.43/enrollments/orgunits/6606/users/&1539872461", sig_body);
+        assert_eq!("OWJpNXhxuYXwPeLL33JmwWuMFX6gaFFHR0JZjUvGPaE", signature(b"key", sig_body.as_bytes()));
+    }
+}
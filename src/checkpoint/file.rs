@@ -0,0 +1,143 @@
+use std::fs::File;
+use std::io::{Read, ErrorKind};
+use std::str::FromStr;
+
+use atomic;
+use super::{CheckpointStore, CheckpointError, Entry};
+
+/// The journal sequence number kept within a file which is replaced
+/// atomically so a crash never leaves a partial value behind. Previous
+/// checkpoints are kept within a history file to roll back to.
+pub struct FileCheckpoint {
+    file: String,
+    /// Number of previous checkpoints kept, none when 0.
+    history: usize,
+    host: String,
+}
+
+impl FileCheckpoint {
+    pub fn new(file: &str, history: usize, host: &str) -> FileCheckpoint {
+        FileCheckpoint {
+            file: file.to_string(),
+            history,
+            host: host.to_string(),
+        }
+    }
+
+    fn history_file(&self) -> String {
+        format!("{}.history", self.file)
+    }
+
+    fn save_entries(&self, entries: &[Entry]) -> Result<(), CheckpointError> {
+        let mut data = String::new();
+        for entry in entries {
+            data.push_str(&format!("{} {}", entry.id, entry.epoch));
+            if let Some(ref host) = entry.host {
+                data.push_str(&format!(" {}", host));
+            }
+            data.push('\n');
+        }
+        atomic::write(&self.history_file(), data.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl CheckpointStore for FileCheckpoint {
+    fn load(&self) -> Result<Option<usize>, CheckpointError> {
+        let data = match read(&self.file)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        match data.trim().parse::<usize>() {
+            Ok(id) => Ok(Some(id)),
+            Err(_) => Err(CheckpointError::Invalid(format!("Invalid journal id {:?} within {}", data, self.file))),
+        }
+    }
+
+    fn save(&self, id: usize, now: i64) -> Result<(), CheckpointError> {
+        atomic::write(&self.file, id.to_string().as_bytes())?;
+        if self.history > 0 {
+            let mut entries = self.entries()?;
+            if entries.last().map(|e| e.id) != Some(id) {
+                entries.push(Entry { id, epoch: now, host: Some(self.host.clone()) });
+                // Keep the latest along with history previous checkpoints
+                let skip = entries.len().saturating_sub(self.history + 1);
+                self.save_entries(&entries[skip..])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, CheckpointError> {
+        let mut entries = Vec::new();
+        if let Some(data) = read(&self.history_file())? {
+            for line in data.lines() {
+                let mut fields = line.split_whitespace();
+                match (fields.next().map(usize::from_str), fields.next().map(i64::from_str)) {
+                    (Some(Ok(id)), Some(Ok(epoch))) => entries.push(Entry { id, epoch, host: fields.next().map(str::to_string) }),
+                    _ => return Err(CheckpointError::Invalid(format!("Invalid checkpoint history entry {:?} within {}", line, self.history_file()))),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn rollback(&self, steps: usize) -> Result<usize, CheckpointError> {
+        let mut entries = self.entries()?;
+        if steps >= entries.len() {
+            return Err(CheckpointError::Invalid(format!("Only {} checkpoints are held within {}", entries.len(), self.history_file())));
+        }
+        entries.truncate(entries.len() - steps);
+        let id = entries[entries.len() - 1].id;
+        atomic::write(&self.file, id.to_string().as_bytes())?;
+        self.save_entries(&entries)?;
+        Ok(id)
+    }
+}
+
+fn read(file: &str) -> Result<Option<String>, CheckpointError> {
+    match File::open(file) {
+        Ok(mut f) => {
+            let mut data = String::new();
+            f.read_to_string(&mut data)?;
+            Ok(Some(data))
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_file_checkpoint() {
+        let file = env::temp_dir().join(format!("d2l-sync-checkpoint-{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        let checkpoint = FileCheckpoint::new(file, 3, "sync-1");
+        assert_eq!(None, checkpoint.load().unwrap());
+
+        for (id, epoch) in [(10, 100), (10, 105), (20, 110), (30, 120), (40, 130), (50, 140)].iter() {
+            checkpoint.save(*id, *epoch).unwrap();
+        }
+        // The latest along with 3 previous checkpoints
+        assert_eq!(Some(50), checkpoint.load().unwrap());
+        let ids: Vec<(usize, i64)> = checkpoint.entries().unwrap().iter().map(|e| (e.id, e.epoch)).collect();
+        assert_eq!(vec![(20, 110), (30, 120), (40, 130), (50, 140)], ids);
+        assert_eq!(Some("sync-1".to_string()), checkpoint.entries().unwrap()[0].host);
+
+        assert_eq!(30, checkpoint.rollback(2).unwrap());
+        assert_eq!(Some(30), checkpoint.load().unwrap());
+        assert_eq!(2, checkpoint.entries().unwrap().len());
+        assert!(checkpoint.rollback(2).is_err());
+
+        atomic::write(file, b"").unwrap();
+        assert!(checkpoint.load().unwrap_err().to_string().contains("--rollback"));
+
+        fs::remove_file(file).unwrap();
+        fs::remove_file(checkpoint.history_file()).unwrap();
+    }
+}
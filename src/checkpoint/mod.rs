@@ -0,0 +1,73 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use source::SourceError;
+
+mod file;
+mod sql;
+
+pub use self::file::FileCheckpoint;
+pub use self::sql::SqlCheckpoint;
+
+/// A previously saved journal id along with when and where it was saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub id: usize,
+    pub epoch: i64,
+    pub host: Option<String>,
+}
+
+/// Where the journal sequence number processed so far is kept.
+pub trait CheckpointStore: Send + Sync {
+    // returns the saved journal id or None when no checkpoint has been saved
+    fn load(&self) -> Result<Option<usize>, CheckpointError>;
+
+    fn save(&self, id: usize, now: i64) -> Result<(), CheckpointError>;
+
+    // returns the previous checkpoints with the most recent last
+    fn entries(&self) -> Result<Vec<Entry>, CheckpointError>;
+
+    // restore the checkpoint saved steps checkpoints before the most
+    // recent one, forgetting every checkpoint saved after it
+    fn rollback(&self, steps: usize) -> Result<usize, CheckpointError>;
+}
+
+/// Name of this host, recorded with each checkpoint.
+pub fn host() -> String {
+    env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    IO(io::Error),
+    Source(SourceError),
+    Invalid(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::IO(err) => write!(f, "{}", err),
+            CheckpointError::Source(err) => write!(f, "{}", err),
+            CheckpointError::Invalid(err) => write!(f, "{}; use --rollback to restore a previous checkpoint or remove the checkpoint to start from the latest journal id", err),
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(err: io::Error) -> CheckpointError {
+        CheckpointError::IO(err)
+    }
+}
+
+impl From<SourceError> for CheckpointError {
+    fn from(err: SourceError) -> CheckpointError {
+        CheckpointError::Source(err)
+    }
+}
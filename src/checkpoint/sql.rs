@@ -0,0 +1,229 @@
+use mysql::{self as my, Pool};
+use postgres::types::ToSql as PgToSql;
+use rusqlite::{Connection, ToSql};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+use source::SourceError;
+use source::postgres::Connection as PgConnection;
+use super::{CheckpointStore, CheckpointError, Entry};
+
+enum Conn {
+    MySql(Pool),
+    Postgres(Box<PgConnection>),
+    Sqlite(Mutex<Connection>),
+}
+
+enum Param<'a> {
+    Int(i64),
+    Text(&'a str),
+}
+
+/// The journal sequence number kept within a table of a writable schema,
+/// one row per checkpoint holding the seqnum, the epoch it was saved, and
+/// the host which saved it. Rows are ordered by an auto-increment key
+/// so the order survives clocks that disagree between hosts.
+pub struct SqlCheckpoint {
+    conn: Conn,
+    table: String,
+    /// Number of checkpoints kept before the latest, which is always kept.
+    history: usize,
+    host: String,
+}
+
+// Map a database error through SourceError which already knows each driver.
+fn sql<E: Into<SourceError>>(err: E) -> CheckpointError {
+    CheckpointError::Source(err.into())
+}
+
+// Postgres numbers its parameters $1, $2 rather than using ?
+fn numbered(query: &str) -> String {
+    let mut n = 0;
+    query.split('?').enumerate().map(|(i, part)| {
+        if i == 0 {
+            part.to_string()
+        } else {
+            n += 1;
+            format!("${}{}", n, part)
+        }
+    }).collect()
+}
+
+impl SqlCheckpoint {
+    pub fn new(uri: &str, table: &str, history: usize, host: &str) -> Result<SqlCheckpoint, CheckpointError> {
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            return Err(CheckpointError::Invalid(format!("Invalid checkpoint table name {:?}", table)));
+        }
+        let conn = match uri.split("://").next() {
+            Some("mysql") => Conn::MySql(Pool::new(uri).map_err(sql)?),
+            Some("postgres") | Some("postgresql") => Conn::Postgres(Box::new(PgConnection::new(uri).map_err(sql)?)),
+            Some("sqlite") => Conn::Sqlite(Mutex::new(Connection::open(uri.trim_start_matches("sqlite://")).map_err(sql)?)),
+            _ => return Err(sql(SourceError::Scheme(uri.split("://").next().unwrap_or("").to_string()))),
+        };
+        let checkpoint = SqlCheckpoint {
+            conn,
+            table: table.to_string(),
+            history,
+            host: host.to_string(),
+        };
+        let key = match checkpoint.conn {
+            Conn::MySql(_) => "BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY",
+            Conn::Postgres(_) => "BIGSERIAL PRIMARY KEY",
+            Conn::Sqlite(_) => "INTEGER PRIMARY KEY AUTOINCREMENT",
+        };
+        checkpoint.execute(&[(format!("CREATE TABLE IF NOT EXISTS {} (id {}, seqnum BIGINT NOT NULL, saved_at BIGINT NOT NULL, host VARCHAR(255) NOT NULL)", table, key), Vec::new())])?;
+        Ok(checkpoint)
+    }
+
+    // Run the statements within a single transaction on one connection
+    // so a failure part way through leaves the table as it was.
+    fn execute(&self, statements: &[(String, Vec<Param>)]) -> Result<(), CheckpointError> {
+        match self.conn {
+            Conn::MySql(ref pool) => {
+                let mut tx = pool.start_transaction(false, None, None).map_err(sql)?;
+                for (query, params) in statements {
+                    let params: Vec<my::Value> = params.iter().map(|p| match *p {
+                        Param::Int(i) => my::Value::from(i),
+                        Param::Text(t) => my::Value::from(t),
+                    }).collect();
+                    tx.prep_exec(query.as_str(), params).map_err(sql)?;
+                }
+                tx.commit().map_err(sql)?;
+            },
+            Conn::Postgres(ref conn) => conn.with(|client| {
+                let mut tx = client.transaction()?;
+                for (query, params) in statements {
+                    let params: Vec<&(dyn PgToSql + Sync)> = params.iter().map(|p| match p {
+                        Param::Int(i) => i as &(dyn PgToSql + Sync),
+                        Param::Text(t) => t as &(dyn PgToSql + Sync),
+                    }).collect();
+                    tx.execute(numbered(query).as_str(), &params)?;
+                }
+                Ok(tx.commit()?)
+            }).map_err(sql)?,
+            Conn::Sqlite(ref conn) => {
+                let mut conn = conn.lock().unwrap();
+                let tx = conn.transaction().map_err(sql)?;
+                for (query, params) in statements {
+                    let params: Vec<&dyn ToSql> = params.iter().map(|p| match p {
+                        Param::Int(i) => i as &dyn ToSql,
+                        Param::Text(t) => t as &dyn ToSql,
+                    }).collect();
+                    tx.execute(query, &params[..]).map_err(sql)?;
+                }
+                tx.commit().map_err(sql)?;
+            },
+        }
+        Ok(())
+    }
+
+    // Every checkpoint from the oldest along with the key of its row.
+    fn rows(&self) -> Result<Vec<(i64, Entry)>, CheckpointError> {
+        let query = format!("SELECT id, seqnum, saved_at, host FROM {} ORDER BY id", self.table);
+        let mut rows: Vec<(i64, i64, i64, String)> = Vec::new();
+        match self.conn {
+            Conn::MySql(ref pool) => for row in pool.prep_exec(query, ()).map_err(sql)? {
+                rows.push(my::from_row::<(i64, i64, i64, String)>(row.map_err(sql)?));
+            },
+            Conn::Postgres(ref conn) => for row in conn.with(|client| Ok(client.query(query.as_str(), &[])?)).map_err(sql)? {
+                rows.push((row.try_get(0).map_err(sql)?, row.try_get(1).map_err(sql)?, row.try_get(2).map_err(sql)?, row.try_get(3).map_err(sql)?));
+            },
+            Conn::Sqlite(ref conn) => {
+                let conn = conn.lock().unwrap();
+                let mut stmt = conn.prepare(&query).map_err(sql)?;
+                let mut result = stmt.query([]).map_err(sql)?;
+                while let Some(row) = result.next().map_err(sql)? {
+                    rows.push((row.get(0).map_err(sql)?, row.get(1).map_err(sql)?, row.get(2).map_err(sql)?, row.get(3).map_err(sql)?));
+                }
+            },
+        }
+        rows.into_iter().map(|(key, seqnum, epoch, host)| {
+            let id = usize::try_from(seqnum).map_err(|_| CheckpointError::Invalid(format!("Invalid journal id {} within {}", seqnum, self.table)))?;
+            Ok((key, Entry { id, epoch, host: Some(host) }))
+        }).collect()
+    }
+
+    fn delete(&self, key: i64) -> (String, Vec<Param<'static>>) {
+        (format!("DELETE FROM {} WHERE id = ?", self.table), vec![Param::Int(key)])
+    }
+}
+
+impl CheckpointStore for SqlCheckpoint {
+    fn load(&self) -> Result<Option<usize>, CheckpointError> {
+        Ok(self.entries()?.last().map(|e| e.id))
+    }
+
+    fn save(&self, id: usize, now: i64) -> Result<(), CheckpointError> {
+        let rows = self.rows()?;
+        if rows.last().map(|r| r.1.id) == Some(id) {
+            return Ok(());
+        }
+        let seqnum = i64::try_from(id).map_err(|_| CheckpointError::Invalid(format!("Journal id {} is too large for {}", id, self.table)))?;
+        let query = format!("INSERT INTO {} (seqnum, saved_at, host) VALUES (?, ?, ?)", self.table);
+        let mut statements = vec![(query, vec![Param::Int(seqnum), Param::Int(now), Param::Text(&self.host)])];
+        // Keep the row just added along with history previous rows
+        let keep = self.history + 1;
+        for &(key, _) in rows.iter().take((rows.len() + 1).saturating_sub(keep)) {
+            statements.push(self.delete(key));
+        }
+        self.execute(&statements)
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, CheckpointError> {
+        Ok(self.rows()?.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    fn rollback(&self, steps: usize) -> Result<usize, CheckpointError> {
+        let rows = self.rows()?;
+        if steps >= rows.len() {
+            return Err(CheckpointError::Invalid(format!("Only {} checkpoints are held within {}", rows.len(), self.table)));
+        }
+        let statements: Vec<_> = rows[rows.len() - steps..].iter().map(|&(key, _)| self.delete(key)).collect();
+        self.execute(&statements)?;
+        Ok(rows[rows.len() - steps - 1].1.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sql_checkpoint() {
+        assert_eq!("INSERT INTO t (seqnum, saved_at, host) VALUES ($1, $2, $3)", numbered("INSERT INTO t (seqnum, saved_at, host) VALUES (?, ?, ?)"));
+        assert!(SqlCheckpoint::new("sqlite://:memory:", "t; DROP TABLE t", 3, "sync-1").is_err());
+
+        let checkpoint = SqlCheckpoint::new("sqlite://:memory:", "d2l_sync_checkpoint", 3, "sync-1").unwrap();
+        assert_eq!(None, checkpoint.load().unwrap());
+        for (id, epoch) in [(10, 100), (10, 105), (20, 110), (30, 120), (40, 130), (50, 140)].iter() {
+            checkpoint.save(*id, *epoch).unwrap();
+        }
+        // The latest along with 3 previous checkpoints
+        assert_eq!(Some(50), checkpoint.load().unwrap());
+        let ids: Vec<(usize, i64)> = checkpoint.entries().unwrap().iter().map(|e| (e.id, e.epoch)).collect();
+        assert_eq!(vec![(20, 110), (30, 120), (40, 130), (50, 140)], ids);
+        assert_eq!(Some("sync-1".to_string()), checkpoint.entries().unwrap()[0].host);
+
+        assert_eq!(30, checkpoint.rollback(2).unwrap());
+        assert_eq!(Some(30), checkpoint.load().unwrap());
+        assert!(checkpoint.rollback(2).is_err());
+
+        // The order saved is kept even when a host's clock runs behind
+        let checkpoint = SqlCheckpoint::new("sqlite://:memory:", "d2l_sync_checkpoint", 1, "sync-1").unwrap();
+        checkpoint.save(10, 200).unwrap();
+        checkpoint.save(20, 100).unwrap();
+        checkpoint.save(30, 150).unwrap();
+        assert_eq!(Some(30), checkpoint.load().unwrap());
+        assert_eq!(20, checkpoint.rollback(1).unwrap());
+        assert!(checkpoint.save(usize::MAX, 160).is_err());
+        checkpoint.execute(&[("INSERT INTO d2l_sync_checkpoint (seqnum, saved_at, host) VALUES (-1, 170, 'sync-2')".to_string(), Vec::new())]).unwrap();
+        assert!(checkpoint.load().is_err());
+
+        // Without history only the latest is kept
+        let checkpoint = SqlCheckpoint::new("sqlite://:memory:", "d2l_sync_checkpoint", 0, "sync-1").unwrap();
+        checkpoint.save(10, 100).unwrap();
+        checkpoint.save(20, 110).unwrap();
+        assert_eq!(1, checkpoint.entries().unwrap().len());
+        assert_eq!(Some(20), checkpoint.load().unwrap());
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicI64;
+use std::time::Duration;
+use toml::Value;
+use toml::value::Table;
+
+use auth::{Authenticator, IdKey, OAuth2};
+use checkpoint::{self, CheckpointStore, CheckpointError, FileCheckpoint, SqlCheckpoint};
+use deprovision::Policy;
+use log::{Level, Format};
+use redact::Pii;
+use retry::Backoff;
+use schemas;
+use source::{self, Settings};
+
+// Every setting by its key within the config file. Each is overridden by
+// the environment variable of the key in upper case prefixed with D2L_,
+// such as D2L_JOURNAL_LIMIT for journal_limit.
+const KEYS: [&str; 51] = [
+    "source", "journal_limit", "journal_id_file", "journal_history", "checkpoint", "checkpoint_table",
+    "query_journal_max_id", "query_journal", "query_user", "query_user_name", "query_users", "query_enrollments",
+    "user_columns", "user_attributes", "name_rules",
+    "ldap_base_dn", "ldap_bind_dn", "ldap_bind_password", "ldap_filter", "ldap_attributes", "ldap_cursor",
+    "uri_base", "auth", "app_id", "app_key", "usr_id", "usr_key",
+    "oauth_client_id", "oauth_client_secret", "oauth_token_uri", "oauth_refresh_token_file",
+    "org_root", "roles", "roles_discover", "role_sync",
+    "deprovision_policy", "deprovision_grace", "deprovision_file", "deprovision_max", "dead_letter_file",
+    "retry_attempts", "retry_delay", "rate_limit", "rate_burst", "throttle_retries",
+    "metrics_addr", "health_max_age", "log_level", "log_format", "log_pii", "log_pii_key",
+];
+
+/// What the process was asked to do, which decides the settings it requires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Follow the journal, the default.
+    Journal,
+    /// Sync the users given by --ids.
+    Ids,
+    Reconcile,
+    Replay,
+    /// Upsert the user given by --data or the rows of a --file.
+    Upsert,
+    /// List or roll back the journal checkpoints.
+    Checkpoints,
+    DeadLetters,
+}
+
+impl Mode {
+    /// Whether users are read from the source.
+    pub fn source(self) -> bool {
+        matches!(self, Mode::Journal | Mode::Ids | Mode::Reconcile | Mode::Replay)
+    }
+
+    // Whether requests are sent to d2l.
+    fn d2l(self) -> bool {
+        self.source() || self == Mode::Upsert
+    }
+}
+
+/// Credentials requests to d2l are authenticated with.
+#[derive(Clone)]
+pub enum Credentials {
+    IdKey {
+        app_id: String,
+        app_key: String,
+        usr_id: String,
+        usr_key: String,
+    },
+    OAuth2 {
+        client_id: String,
+        client_secret: String,
+        token_uri: String,
+        refresh_token_file: String,
+    },
+}
+
+/// Every setting of the process, validated as a whole when loaded.
+/// Debug is not derived as the credentials must never be logged.
+pub struct Config {
+    /// Connection uri of the "warehouse" holding the journal and users.
+    pub source: Option<String>,
+    pub source_settings: Settings,
+    /// Journal events read at a time, required to follow the journal.
+    pub journal_limit: usize,
+    pub journal_id_file: Option<String>,
+    /// Previous journal id checkpoints kept so the journal may be rolled back.
+    pub journal_history: usize,
+    /// Connection uri of a writable schema where the journal id is kept
+    /// in place of the journal_id_file.
+    pub checkpoint_uri: Option<String>,
+    pub checkpoint_table: String,
+    pub uri_base: String,
+    /// None when the mode never sends requests to d2l.
+    pub credentials: Option<Credentials>,
+    /// Org unit id of the organization root where org-level
+    /// roles are enrolled; discovered from d2l when not set.
+    pub org_root: Option<usize>,
+    /// Replaces the default role catalog, such as "Faculty=109,TA=111".
+    pub roles: Option<String>,
+    /// Adds every role defined within d2l to the catalog by its display name.
+    pub roles_discover: bool,
+    /// Compares the org-level role of existing users with their source role.
+    pub role_sync: bool,
+    pub deprovision_policy: Policy,
+    /// Seconds a user must be missing from the source before
+    /// the deprovision policy is applied.
+    pub deprovision_grace: i64,
+    pub deprovision_file: Option<String>,
+    /// Most d2l accounts a reconcile deprovisions, none are when more are found.
+    pub deprovision_max: usize,
+    pub dead_letter_file: Option<String>,
+    pub backoff: Backoff,
+    /// Requests per second to d2l along with the burst allowed.
+    pub rate_limit: Option<(f64, f64)>,
+    /// Times a request is retried after d2l responds with a 429 or 503 status.
+    pub throttle_retries: u32,
+    pub metrics_addr: Option<String>,
+    /// Seconds without a processed user or journal checkpoint before /healthz fails.
+    pub health_max_age: i64,
+    pub log_level: Level,
+    pub log_format: Format,
+    pub log_pii: Pii,
+    pub log_pii_key: Option<Vec<u8>>,
+}
+
+// The environment variable overriding a setting.
+fn var(key: &str) -> String {
+    format!("D2L_{}", key.to_uppercase())
+}
+
+// Raw values of the settings by key, along with every problem found with them.
+#[derive(Default)]
+struct Layers {
+    values: BTreeMap<&'static str, String>,
+    errors: Vec<String>,
+}
+
+impl Layers {
+    fn file(&mut self, path: &str) {
+        match fs::read_to_string(path) {
+            Ok(text) => self.toml(&text, path),
+            Err(e) => self.errors.push(format!("Unable to read config file {:?}: {}", path, e)),
+        }
+    }
+
+    fn toml(&mut self, text: &str, path: &str) {
+        match toml::from_str::<Table>(text) {
+            Ok(table) => self.table("", table, path),
+            Err(e) => self.errors.push(format!("Invalid config file {:?}: {}", path, e)),
+        }
+    }
+
+    // Tables are flattened into keys joined with "_" so "user" within
+    // [query] is query_user, arrays of strings are joined with ",".
+    fn table(&mut self, prefix: &str, table: Table, path: &str) {
+        for (name, value) in table {
+            let name = format!("{}{}", prefix, name);
+            let value = match value {
+                Value::Table(table) => {
+                    self.table(&format!("{}_", name), table, path);
+                    continue;
+                },
+                Value::String(s) => Some(s),
+                Value::Integer(i) => Some(i.to_string()),
+                Value::Float(f) => Some(f.to_string()),
+                Value::Boolean(b) => Some(b.to_string()),
+                Value::Array(values) => values.iter()
+                    .map(|v| v.as_str())
+                    .collect::<Option<Vec<&str>>>()
+                    .map(|values| values.join(",")),
+                Value::Datetime(_) => None,
+            };
+            match (KEYS.iter().find(|&&k| k == name), value) {
+                (Some(key), Some(value)) => { self.values.insert(key, value); },
+                (Some(_), None) => self.errors.push(format!("Unsupported value of {} within config file {:?}", name, path)),
+                (None, _) => self.errors.push(format!("Unknown setting {} within config file {:?}", name, path)),
+            }
+        }
+    }
+
+    fn env(&mut self) {
+        for key in KEYS.iter() {
+            if let Ok(value) = env::var(var(key)) {
+                self.values.insert(key, value);
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.values.get(key).cloned()
+    }
+
+    // A setting which must be set when required.
+    fn required(&mut self, key: &str, required: bool) -> Option<String> {
+        let value = self.get(key);
+        if required && value.is_none() {
+            self.errors.push(format!("{} ({}) is required", key, var(key)));
+        }
+        value
+    }
+
+    fn parse_with<T, E, F>(&mut self, key: &str, parse: F) -> Option<T>
+        where E: Display, F: FnOnce(&str) -> Result<T, E>
+    {
+        let value = self.get(key)?;
+        match parse(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("Invalid {} ({}) value: {}", key, var(key), e));
+                None
+            },
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str) -> Option<T> where T::Err: Display {
+        self.parse_with(key, str::parse)
+    }
+
+    // A number from min through max, so a value such as a negative grace
+    // period is reported at startup rather than misbehaving once running.
+    fn range<T>(&mut self, key: &str, min: T, max: T) -> Option<T>
+        where T: FromStr + PartialOrd + Display, T::Err: Display
+    {
+        self.parse_with(key, |value| match value.parse::<T>() {
+            Ok(value) if value < min => Err(format!("{} is below the minimum of {}", value, min)),
+            Ok(value) if value > max => Err(format!("{} is above the maximum of {}", value, max)),
+            Ok(value) => Ok(value),
+            Err(e) => Err(e.to_string()),
+        })
+    }
+}
+
+impl Config {
+    /// Load the settings from their defaults, then the optional config
+    /// file, and then the D2L_ environment variables. Every missing or
+    /// invalid setting for the mode is returned at once.
+    pub fn load(path: Option<&str>, mode: Mode) -> Result<Config, Vec<String>> {
+        let mut layers = Layers::default();
+        if let Some(path) = path {
+            layers.file(path);
+        }
+        layers.env();
+        Config::build(layers, mode)
+    }
+
+    fn build(mut s: Layers, mode: Mode) -> Result<Config, Vec<String>> {
+        let source = s.required("source", mode.source());
+        let ldap = match source {
+            Some(ref uri) => source::scheme(uri).unwrap_or_else(|| {
+                s.errors.push(format!("Unsupported scheme of source ({}), expected mysql, postgres, sqlite, or ldap", var("source")));
+                false
+            }),
+            None => false,
+        };
+        // Only SQL sources are queried, a directory is searched instead
+        let sql = mode.source() && !ldap;
+        let journal = mode == Mode::Journal;
+        let deprovision_policy = s.parse("deprovision_policy").unwrap_or(Policy::Ignore);
+        let source_settings = Settings {
+            query_journal_max_id: s.required("query_journal_max_id", sql && journal),
+            query_journal: s.required("query_journal", sql && journal),
+            query_user: s.required("query_user", sql),
+            query_user_name: s.required("query_user_name", sql && journal && deprovision_policy != Policy::Ignore),
+            query_users: s.required("query_users", sql && mode == Mode::Reconcile),
+            query_enrollments: s.get("query_enrollments"),
+            user_columns: s.parse("user_columns").unwrap_or_default(),
+            user_attributes: s.parse_with("user_attributes", source::parse_attributes).unwrap_or_default(),
+            name_rules: s.parse("name_rules").unwrap_or_default(),
+            ldap_base_dn: s.required("ldap_base_dn", mode.source() && ldap).unwrap_or_default(),
+            ldap_bind_dn: s.get("ldap_bind_dn"),
+            ldap_bind_password: s.get("ldap_bind_password").unwrap_or_default(),
+            ldap_filter: s.get("ldap_filter").unwrap_or_else(|| "(objectClass=person)".to_string()),
+            ldap_attributes: s.parse("ldap_attributes").unwrap_or_default(),
+            ldap_cursor: s.parse("ldap_cursor").unwrap_or_default(),
+        };
+
+        s.required("journal_limit", journal);
+        let journal_limit = s.range("journal_limit", 1, usize::MAX).unwrap_or(0);
+        let checkpoint_uri = s.get("checkpoint");
+        let journal_id_file = s.required("journal_id_file", checkpoint_uri.is_none() && (journal || mode == Mode::Checkpoints));
+
+        let d2l = mode.d2l();
+        let credentials = match s.get("auth").as_ref().map_or("IdKey", String::as_str) {
+            "IdKey" => match (s.required("app_id", d2l), s.required("app_key", d2l), s.required("usr_id", d2l), s.required("usr_key", d2l)) {
+                (Some(app_id), Some(app_key), Some(usr_id), Some(usr_key)) => Some(Credentials::IdKey { app_id, app_key, usr_id, usr_key }),
+                _ => None,
+            },
+            "OAuth2" => match (s.required("oauth_client_id", d2l), s.required("oauth_client_secret", d2l), s.required("oauth_refresh_token_file", d2l)) {
+                (Some(client_id), Some(client_secret), Some(refresh_token_file)) => Some(Credentials::OAuth2 {
+                    client_id,
+                    client_secret,
+                    token_uri: s.get("oauth_token_uri").unwrap_or_else(|| "https://auth.brightspace.com/core/connect/token".to_string()),
+                    refresh_token_file,
+                }),
+                _ => None,
+            },
+            auth => {
+                s.errors.push(format!("Invalid auth ({}) value {:?}, expected IdKey or OAuth2", var("auth"), auth));
+                None
+            },
+        };
+
+        let config = Config {
+            source,
+            source_settings,
+            journal_limit,
+            journal_id_file,
+            journal_history: s.parse("journal_history").unwrap_or(0),
+            checkpoint_uri,
+            checkpoint_table: s.get("checkpoint_table").unwrap_or_else(|| "d2l_sync_checkpoint".to_string()),
+            uri_base: s.get("uri_base").unwrap_or_else(|| "https://test.brightspace.com".to_string()),
+            credentials,
+            org_root: s.parse("org_root"),
+            roles: s.parse_with("roles", |roles| schemas::parse_catalog(roles).map(|_| roles.to_string())),
+            roles_discover: s.parse("roles_discover").unwrap_or(false),
+            role_sync: s.parse("role_sync").unwrap_or(true),
+            deprovision_policy,
+            deprovision_grace: s.range("deprovision_grace", 0, i64::MAX).unwrap_or(0),
+            deprovision_file: s.get("deprovision_file"),
+            deprovision_max: s.parse("deprovision_max").unwrap_or(100),
+            // Following the journal moves past failed users, which are only kept within the file
+            dead_letter_file: s.required("dead_letter_file", journal),
+            backoff: Backoff {
+                // The delay doubles on each attempt so both are bounded
+                attempts: s.range("retry_attempts", 1, 10).unwrap_or(3),
+                delay: Duration::from_millis(s.range("retry_delay", 0, 600_000).unwrap_or(1000)),
+            },
+            rate_limit: s.parse_with("rate_limit", |rate| match rate.parse::<f64>() {
+                Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+                Ok(_) => Err("must be greater than 0".to_string()),
+                Err(e) => Err(e.to_string()),
+            }).map(|rate| (rate, s.range("rate_burst", 1.0, f64::MAX).unwrap_or_else(|| rate.max(1.0)))),
+            throttle_retries: s.range("throttle_retries", 0, 100).unwrap_or(5),
+            metrics_addr: s.get("metrics_addr"),
+            health_max_age: s.range("health_max_age", 1, i64::MAX).unwrap_or(300),
+            log_level: s.parse("log_level").unwrap_or(Level::Info),
+            log_format: s.parse("log_format").unwrap_or(Format::Text),
+            log_pii: s.parse("log_pii").unwrap_or(Pii::Show),
+            log_pii_key: s.get("log_pii_key").map(String::into_bytes),
+        };
+        if s.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(s.errors)
+        }
+    }
+
+    /// Authenticates requests to d2l, None when no credentials were required.
+    pub fn authenticator(&self) -> Option<Box<dyn Authenticator>> {
+        match self.credentials.clone()? {
+            Credentials::IdKey { app_id, app_key, usr_id, usr_key } => Some(Box::new(IdKey {
+                app_id,
+                app_key: app_key.into_bytes(),
+                usr_id,
+                usr_key: usr_key.into_bytes(),
+                skew: AtomicI64::new(0),
+            })),
+            Credentials::OAuth2 { client_id, client_secret, token_uri, refresh_token_file } => Some(Box::new(OAuth2 {
+                client_id,
+                client_secret,
+                token_uri,
+                refresh_token_file,
+                token: Mutex::new(None),
+            })),
+        }
+    }
+
+    /// The journal id checkpoints, kept within the checkpoint
+    /// table when a checkpoint uri is set or else the journal id file.
+    pub fn checkpoints(&self) -> Result<Box<dyn CheckpointStore>, CheckpointError> {
+        match (&self.checkpoint_uri, &self.journal_id_file) {
+            (Some(uri), _) => Ok(Box::new(SqlCheckpoint::new(uri, &self.checkpoint_table, self.journal_history, &checkpoint::host())?)),
+            (None, Some(file)) => Ok(Box::new(FileCheckpoint::new(file, self.journal_history, &checkpoint::host()))),
+            (None, None) => Err(CheckpointError::Invalid("journal_id_file (D2L_JOURNAL_ID_FILE) or checkpoint (D2L_CHECKPOINT) is required".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layers(text: &str) -> Layers {
+        let mut layers = Layers::default();
+        layers.toml(text, "d2l-sync.toml");
+        layers
+    }
+
+    #[test]
+    fn test_layers() {
+        let layers = layers(r#"
+            source = "sqlite://d2l.db"
+            journal_limit = 100
+            roles = ["Faculty=109", "TA=111"]
+            [query]
+            user = "SELECT * FROM users WHERE uid = ?1"
+            [ldap]
+            base_dn = "ou=people,dc=txstate,dc=edu"
+            nickname = "cn"
+        "#);
+        assert_eq!(Some("100".to_string()), layers.get("journal_limit"));
+        assert_eq!(Some("Faculty=109,TA=111".to_string()), layers.get("roles"));
+        assert_eq!(Some("SELECT * FROM users WHERE uid = ?1".to_string()), layers.get("query_user"));
+        assert_eq!(Some("ou=people,dc=txstate,dc=edu".to_string()), layers.get("ldap_base_dn"));
+        assert_eq!(vec![r#"Unknown setting ldap_nickname within config file "d2l-sync.toml""#.to_string()], layers.errors);
+        assert_eq!(1, self::layers("source = ").errors.len());
+    }
+
+    #[test]
+    fn test_build() {
+        let errors = Config::build(layers(r#"
+            source = "sqlite://d2l.db"
+            journal_limit = "many"
+            log_level = "trace"
+            retry_attempts = 40
+            deprovision_grace = -1
+            rate_limit = 0
+            [query]
+            user = "SELECT * FROM users WHERE uid = ?1"
+        "#), Mode::Journal).err().unwrap();
+        assert_eq!(vec![
+            "query_journal_max_id (D2L_QUERY_JOURNAL_MAX_ID) is required",
+            "query_journal (D2L_QUERY_JOURNAL) is required",
+            "Invalid journal_limit (D2L_JOURNAL_LIMIT) value: invalid digit found in string",
+            "journal_id_file (D2L_JOURNAL_ID_FILE) is required",
+            "app_id (D2L_APP_ID) is required",
+            "app_key (D2L_APP_KEY) is required",
+            "usr_id (D2L_USR_ID) is required",
+            "usr_key (D2L_USR_KEY) is required",
+            "Invalid deprovision_grace (D2L_DEPROVISION_GRACE) value: -1 is below the minimum of 0",
+            "dead_letter_file (D2L_DEAD_LETTER_FILE) is required",
+            "Invalid retry_attempts (D2L_RETRY_ATTEMPTS) value: 40 is above the maximum of 10",
+            "Invalid rate_limit (D2L_RATE_LIMIT) value: must be greater than 0",
+            "Invalid log_level (D2L_LOG_LEVEL) value: Unknown log level \"trace\", expected one of Error, Warn, Info, Debug",
+        ], errors);
+
+        // Listing the dead letters requires no settings at all
+        let config = Config::build(Layers::default(), Mode::DeadLetters).ok().unwrap();
+        assert!(config.credentials.is_none());
+        assert_eq!(3, config.backoff.attempts);
+        assert_eq!(300, config.health_max_age);
+        assert_eq!(100, config.deprovision_max);
+
+        let config = Config::build(layers(r#"
+            source = "ldap://ldap.txstate.edu"
+            auth = "OAuth2"
+            rate_limit = 2.5
+            [ldap]
+            base_dn = "ou=people,dc=txstate,dc=edu"
+            [oauth]
+            client_id = "d2l-sync"
+            client_secret = "secret"
+            refresh_token_file = "refresh_token"
+        "#), Mode::Ids).ok().unwrap();
+        assert!(config.source_settings.query_user.is_none());
+        assert_eq!(Some((2.5, 2.5)), config.rate_limit);
+        assert!(config.authenticator().is_some());
+    }
+}
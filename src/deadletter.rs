@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::{Result, ErrorKind};
+use std::str::FromStr;
+
+use atomic;
+
+/// A user whose upsert failed after exhausting all retries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub uid: usize,
+    pub attempts: usize,
+    pub epoch: i64,
+    pub error: String,
+}
+
+/// Internal user ids which failed to sync so they may be listed and
+/// replayed later instead of being dropped once the journal moves on.
+/// When a file is provided the store survives restarts.
+pub struct DeadLetters {
+    file: Option<String>,
+    entries: BTreeMap<usize, Entry>,
+}
+
+impl DeadLetters {
+    pub fn load(file: Option<&str>) -> Result<DeadLetters> {
+        let mut entries = BTreeMap::new();
+        if let Some(file) = file {
+            match File::open(file) {
+                Ok(mut f) => {
+                    let mut data = String::new();
+                    f.read_to_string(&mut data)?;
+                    for line in data.lines() {
+                        let entry = parse(line).ok_or_else(|| {
+                            std::io::Error::new(ErrorKind::InvalidData, format!("Invalid dead letter entry {:?}", line))
+                        })?;
+                        entries.insert(entry.uid, entry);
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(DeadLetters {
+            file: file.map(|f| f.to_string()),
+            entries,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(ref file) = self.file {
+            let mut data = String::new();
+            for entry in self.entries.values() {
+                data.push_str(&format!("{} {} {} {}\n", entry.uid, entry.attempts, entry.epoch, entry.error));
+            }
+            atomic::write(file, data.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Record a failed user; repeated failures bump the attempts.
+    pub fn push(&mut self, uid: usize, epoch: i64, error: &str) {
+        let error = error.replace(|c: char| c.is_control(), " ");
+        let entry = self.entries.entry(uid).or_insert(Entry { uid, attempts: 0, epoch, error: String::new() });
+        entry.attempts += 1;
+        entry.epoch = epoch;
+        entry.error = error;
+    }
+
+    pub fn remove(&mut self, uid: usize) -> bool {
+        self.entries.remove(&uid).is_some()
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.values().cloned().collect()
+    }
+}
+
+fn parse(line: &str) -> Option<Entry> {
+    let mut fields = line.splitn(4, ' ');
+    Some(Entry {
+        uid: usize::from_str(fields.next()?).ok()?,
+        attempts: usize::from_str(fields.next()?).ok()?,
+        epoch: i64::from_str(fields.next()?).ok()?,
+        error: fields.next().unwrap_or("").to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_parse() {
+        let expected = Entry { uid: 1234, attempts: 2, epoch: 1539872461, error: "StatusCode(500) Internal Server Error".to_string() };
+        assert_eq!(Some(expected), parse("1234 2 1539872461 StatusCode(500) Internal Server Error"));
+        assert_eq!(Some(Entry { uid: 1234, attempts: 1, epoch: 0, error: String::new() }), parse("1234 1 0"));
+        assert_eq!(None, parse("1234 x 1539872461 error"));
+        assert_eq!(None, parse("1234"));
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let file = env::temp_dir().join(format!("d2l-sync-dead-letters-{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        let mut dead_letters = DeadLetters::load(Some(file)).unwrap();
+        assert!(dead_letters.entries().is_empty());
+
+        dead_letters.push(10, 100, "StatusCode(500)");
+        dead_letters.push(20, 105, "Http(timed out)");
+        dead_letters.push(10, 110, "Json(\nmissing field)");
+        assert!(dead_letters.remove(20));
+        assert!(!dead_letters.remove(30));
+        dead_letters.save().unwrap();
+
+        let expected = vec![Entry { uid: 10, attempts: 2, epoch: 110, error: "Json( missing field)".to_string() }];
+        assert_eq!(expected, DeadLetters::load(Some(file)).unwrap().entries());
+
+        fs::write(file, "10 two 110 error\n").unwrap();
+        assert!(DeadLetters::load(Some(file)).is_err());
+        fs::remove_file(file).unwrap();
+
+        let dead_letters = DeadLetters::load(None).unwrap();
+        dead_letters.save().unwrap();
+    }
+}
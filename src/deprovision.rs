@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::{Result, ErrorKind};
+use std::str::FromStr;
+
+use atomic;
+use schemas::{ParseError, UserReadOrUpdate};
+
+/// Action taken on a d2l account once its user has
+/// disappeared from the source for longer than the
+/// configured grace period.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    Deactivate,
+    Ignore,
+    Delete,
+}
+
+impl FromStr for Policy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "Deactivate" => Ok(Policy::Deactivate),
+            "Ignore" => Ok(Policy::Ignore),
+            "Delete" => Ok(Policy::Delete),
+            _ => Err(ParseError::new("Invalid Deprovision policy")),
+        }
+    }
+}
+
+impl Policy {
+    /// Whether the policy would change the d2l account.
+    pub fn applies(self, user: &UserReadOrUpdate) -> bool {
+        match self {
+            Policy::Deactivate => user.activation.is_active,
+            Policy::Delete => true,
+            Policy::Ignore => false,
+        }
+    }
+}
+
+/// The accounts only found in d2l which carry an OrgDefinedId and would be
+/// changed by the policy. When there are more than max none are returned,
+/// only their count, as a source which suddenly returns few users would
+/// otherwise deprovision most of d2l in a single run.
+pub fn missing<'a, I>(policy: Policy, max: usize, accounts: I) -> std::result::Result<Vec<&'a UserReadOrUpdate>, usize>
+    where I: IntoIterator<Item = &'a UserReadOrUpdate>
+{
+    let missing: Vec<&UserReadOrUpdate> = accounts.into_iter()
+        .filter(|a| a.user_base.org_defined_id.as_ref().is_some_and(|id| !id.is_empty()))
+        .filter(|a| policy.applies(a))
+        .collect();
+    if missing.len() > max {
+        Err(missing.len())
+    } else {
+        Ok(missing)
+    }
+}
+
+/// Internal user ids which were not found in the source along
+/// with the epoch they were first noticed missing. When a file
+/// is provided the list survives restarts so the grace period
+/// is not reset every time the process is redeployed.
+pub struct Pending {
+    file: Option<String>,
+    users: BTreeMap<usize, i64>,
+}
+
+impl Pending {
+    pub fn load(file: Option<&str>) -> Result<Pending> {
+        let mut users = BTreeMap::new();
+        if let Some(file) = file {
+            match File::open(file) {
+                Ok(mut f) => {
+                    let mut data = String::new();
+                    f.read_to_string(&mut data)?;
+                    for line in data.lines() {
+                        let mut fields = line.split_whitespace();
+                        match (fields.next().map(usize::from_str), fields.next().map(i64::from_str)) {
+                            (Some(Ok(uid)), Some(Ok(epoch))) => { users.insert(uid, epoch); },
+                            _ => return Err(std::io::Error::new(ErrorKind::InvalidData, format!("Invalid deprovision entry {:?}", line))),
+                        }
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Pending {
+            file: file.map(|f| f.to_string()),
+            users,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(ref file) = self.file {
+            let mut data = String::new();
+            for (uid, epoch) in &self.users {
+                data.push_str(&format!("{} {}\n", uid, epoch));
+            }
+            atomic::write(file, data.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Record a user as missing; keeps the original
+    /// epoch if the user was already pending.
+    pub fn mark(&mut self, uid: usize, now: i64) {
+        self.users.entry(uid).or_insert(now);
+    }
+
+    /// Remove a user that has reappeared or been deprovisioned.
+    pub fn clear(&mut self, uid: usize) -> bool {
+        self.users.remove(&uid).is_some()
+    }
+
+    /// Users that have been missing for at least grace seconds.
+    pub fn due(&self, grace: i64, now: i64) -> Vec<usize> {
+        self.users.iter()
+            .filter(|&(_, &epoch)| now - epoch >= grace)
+            .map(|(&uid, _)| uid)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemas::{Activation, UserBase};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(Policy::Deactivate, "Deactivate".parse::<Policy>().unwrap());
+        assert_eq!(Policy::Ignore, "Ignore".parse::<Policy>().unwrap());
+        assert_eq!(Policy::Delete, "Delete".parse::<Policy>().unwrap());
+        assert!("delete".parse::<Policy>().is_err());
+        assert!("".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn test_missing() {
+        let account = |user_id: usize, is_active: bool| UserReadOrUpdate {
+            user_base: UserBase {
+                user_name: format!("user{}", user_id),
+                org_defined_id: Some(format!("A{:08}", user_id)),
+                ..UserBase::default()
+            },
+            user_id,
+            activation: Activation { is_active },
+        };
+        // Accounts deactivated by earlier runs do not count against the cap
+        let mut accounts: Vec<UserReadOrUpdate> = (0..150).map(|id| account(id, false)).collect();
+        accounts.push(account(150, true));
+        accounts.push(UserReadOrUpdate { user_base: UserBase { org_defined_id: None, ..UserBase::default() }, ..account(151, true) });
+        let ids: Vec<usize> = missing(Policy::Deactivate, 100, &accounts).unwrap().iter().map(|a| a.user_id).collect();
+        assert_eq!(vec![150], ids);
+
+        // Every account with an OrgDefinedId would be deleted
+        assert_eq!(Err(151), missing(Policy::Delete, 100, &accounts).map(|m| m.len()));
+        assert_eq!(Ok(0), missing(Policy::Ignore, 100, &accounts).map(|m| m.len()));
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut pending = Pending::load(None).unwrap();
+        pending.mark(10, 100);
+        pending.mark(20, 150);
+        // Marking again keeps the epoch the user was first missing
+        pending.mark(10, 190);
+        assert_eq!(Vec::<usize>::new(), pending.due(100, 190));
+        assert_eq!(vec![10], pending.due(100, 200));
+        assert_eq!(vec![10, 20], pending.due(50, 200));
+        assert_eq!(vec![10, 20], pending.due(0, 150));
+
+        assert!(pending.clear(10));
+        assert!(!pending.clear(10));
+        assert_eq!(vec![20], pending.due(0, 200));
+        // Without a file nothing is written
+        pending.save().unwrap();
+    }
+
+    #[test]
+    fn test_pending_file() {
+        let file = env::temp_dir().join(format!("d2l-sync-pending-{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        let mut pending = Pending::load(Some(file)).unwrap();
+        pending.mark(10, 100);
+        pending.mark(20, 200);
+        pending.save().unwrap();
+
+        let pending = Pending::load(Some(file)).unwrap();
+        assert_eq!(vec![10, 20], pending.due(0, 200));
+
+        fs::write(file, "10\n").unwrap();
+        assert!(Pending::load(Some(file)).is_err());
+        fs::write(file, "10 yesterday\n").unwrap();
+        assert!(Pending::load(Some(file)).is_err());
+        fs::remove_file(file).unwrap();
+    }
+}
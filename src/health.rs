@@ -0,0 +1,117 @@
+use hyper::StatusCode;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use source::Source;
+use sync::Sync;
+
+/// Outcome of probing a dependency.
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new<T, E: Display>(result: Result<T, E>) -> Check {
+        match result {
+            Ok(_) => Check { ok: true, error: None },
+            Err(e) => Check { ok: false, error: Some(e.to_string()) },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    d2l: Option<Check>,
+    /// Seconds since the last processed user or journal checkpoint.
+    progress_age: i64,
+}
+
+impl Report {
+    fn respond(&self) -> (StatusCode, String) {
+        let status = if self.ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        (status, serde_json::to_string(self).expect("Unable to serialize health report"))
+    }
+}
+
+/// Epoch of the last processed user or successful journal checkpoint,
+/// or of startup until then.
+static PROGRESS: AtomicI64 = AtomicI64::new(0);
+
+/// Record that the process is still making progress, whichever mode it runs in.
+pub fn progressed(now: i64) {
+    PROGRESS.store(now, Ordering::Relaxed);
+}
+
+/// Liveness and readiness of the process for container orchestration.
+/// The process is live while users keep being processed or journal
+/// checkpoints saved and ready while both the source and d2l are reachable.
+pub struct Health {
+    source: Option<Arc<dyn Source>>,
+    sync: Arc<Sync>,
+    /// Seconds without progress before the process is no longer live.
+    max_age: i64,
+}
+
+impl Health {
+    pub fn new(source: Option<Arc<dyn Source>>, sync: Arc<Sync>, max_age: i64, now: i64) -> Health {
+        progressed(now);
+        Health { source, sync, max_age }
+    }
+
+    fn age(&self, now: i64) -> i64 {
+        now - PROGRESS.load(Ordering::Relaxed)
+    }
+
+    /// Fails once no user has been processed nor the journal checkpointed
+    /// within max_age, such as when the process is wedged on a request.
+    pub fn healthz(&self, now: i64) -> (StatusCode, String) {
+        let age = self.age(now);
+        Report { ok: age <= self.max_age, source: None, d2l: None, progress_age: age }.respond()
+    }
+
+    /// Fails while either the source or d2l cannot be reached.
+    pub fn readyz(&self, now: i64) -> (StatusCode, String) {
+        let source = self.source.as_ref().map(|db| Check::new(db.ping()));
+        // Only the variant and status are reported as the error holds the signed request uri
+        let d2l = Check::new(self.sync.whoami().map_err(|e| match e.status() {
+            Some(status) => format!("{} {}", e.variant(), status),
+            None => e.variant().to_string(),
+        }));
+        Report {
+            ok: d2l.ok && source.as_ref().map_or(true, |c| c.ok),
+            source,
+            d2l: Some(d2l),
+            progress_age: self.age(now),
+        }.respond()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let report = Report {
+            ok: false,
+            source: Some(Check::new::<(), _>(Err("connection refused"))),
+            d2l: Some(Check::new::<_, String>(Ok(()))),
+            progress_age: 12,
+        };
+        let (status, body) = report.respond();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(r#"{"ok":false,"source":{"ok":false,"error":"connection refused"},"d2l":{"ok":true},"progress_age":12}"#, body);
+
+        let (status, body) = Report { ok: true, source: None, d2l: None, progress_age: 3 }.respond();
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"ok":true,"progress_age":3}"#, body);
+    }
+}
@@ -0,0 +1,226 @@
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::str::FromStr;
+
+use schemas::ParseError;
+use redact;
+
+/// Severity of a log line, each level includes those before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for Level {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(ParseError::new(format!("Unknown log level {:?}, expected one of Error, Warn, Info, Debug", s))),
+        }
+    }
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "Error",
+            Level::Warn => "Warn",
+            Level::Info => "Info",
+            Level::Debug => "Debug",
+        }
+    }
+}
+
+/// How each log line is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The message followed by key=value fields.
+    Text,
+    /// One JSON object per line for log aggregators.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(ParseError::new(format!("Unknown log format {:?}, expected Text or Json", s))),
+        }
+    }
+}
+
+// The most verbose level written and how each line is written,
+// Info and Text until init is called with the configured values.
+static SETTINGS: OnceLock<(Level, Format)> = OnceLock::new();
+
+/// Set the level, such as Debug which adds every d2l request, and the
+/// format of the lines written; only the first call has any effect.
+pub fn init(level: Level, format: Format) {
+    let _ = SETTINGS.set((level, format));
+}
+
+fn settings() -> (Level, Format) {
+    SETTINGS.get().cloned().unwrap_or((Level::Info, Format::Text))
+}
+
+/// Correlation fields of a log line, those left as None are not written.
+#[derive(Debug, Default)]
+pub struct Fields<'a> {
+    /// Journal sequence number.
+    pub seqnum: Option<usize>,
+    /// Internal user id within the source.
+    pub uid: Option<usize>,
+    /// D2L user id.
+    pub user_id: Option<usize>,
+    pub user_name: Option<&'a str>,
+    /// Row of a batch file.
+    pub row: Option<usize>,
+    pub org_unit_id: Option<usize>,
+    /// Outcome or operation such as updated or deprovision.
+    pub action: Option<&'a str>,
+    pub method: Option<&'a str>,
+    pub path: Option<&'a str>,
+    /// HTTP status d2l responded with.
+    pub status: Option<u16>,
+    pub duration: Option<Duration>,
+    pub error: Option<String>,
+}
+
+impl<'a> Fields<'a> {
+    // The fields which are set in the order they are written.
+    fn pairs(&self) -> Vec<(&'static str, Value)> {
+        let mut pairs = Vec::new();
+        let mut push = |key, value: Option<Value>| if let Some(value) = value {
+            pairs.push((key, value));
+        };
+        push("seqnum", self.seqnum.map(Value::from));
+        push("uid", self.uid.map(Value::from));
+        push("user_id", self.user_id.map(Value::from));
+        push("user_name", self.user_name.map(|u| Value::from(redact::pii(u).into_owned())));
+        push("row", self.row.map(Value::from));
+        push("org_unit_id", self.org_unit_id.map(Value::from));
+        push("action", self.action.map(Value::from));
+        push("method", self.method.map(Value::from));
+        push("path", self.path.map(Value::from));
+        push("status", self.status.map(Value::from));
+        push("duration_ms", self.duration.map(|d| Value::from(d.as_millis() as u64)));
+        push("error", self.error.as_ref().map(|e| Value::from(redact::error(e))));
+        pairs
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= settings().0
+}
+
+pub fn error(message: &str, fields: Fields) {
+    log(Level::Error, message, fields);
+}
+
+pub fn warn(message: &str, fields: Fields) {
+    log(Level::Warn, message, fields);
+}
+
+pub fn info(message: &str, fields: Fields) {
+    log(Level::Info, message, fields);
+}
+
+pub fn debug(message: &str, fields: Fields) {
+    log(Level::Debug, message, fields);
+}
+
+/// Write a line at the level, errors and warnings to stderr and the rest to stdout.
+pub fn log(level: Level, message: &str, fields: Fields) {
+    if !enabled(level) {
+        return;
+    }
+    let line = match settings().1 {
+        Format::Text => text(level, message, &fields),
+        Format::Json => json(level, message, &fields, &Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+    };
+    if level <= Level::Warn {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+fn text(level: Level, message: &str, fields: &Fields) -> String {
+    let mut line = format!("{}: {}", level.name(), message);
+    for (key, value) in fields.pairs() {
+        match value {
+            // Quote strings which would otherwise be ambiguous
+            Value::String(ref s) if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') => {
+                line.push_str(&format!(" {}={:?}", key, s));
+            },
+            Value::String(s) => line.push_str(&format!(" {}={}", key, s)),
+            value => line.push_str(&format!(" {}={}", key, value)),
+        }
+    }
+    line
+}
+
+fn json(level: Level, message: &str, fields: &Fields, time: &str) -> String {
+    let mut object = Map::new();
+    object.insert("time".to_string(), Value::from(time));
+    object.insert("level".to_string(), Value::from(level.name().to_lowercase()));
+    object.insert("message".to_string(), Value::from(message));
+    for (key, value) in fields.pairs() {
+        object.insert(key.to_string(), value);
+    }
+    Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields<'static> {
+        Fields {
+            seqnum: Some(42),
+            uid: Some(7),
+            user_name: Some("j_d1"),
+            action: Some("updated"),
+            duration: Some(Duration::from_millis(120)),
+            error: Some("connection reset".to_string()),
+            ..Fields::default()
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(r#"Info: Updated seqnum=42 uid=7 user_name=j_d1 action=updated duration_ms=120 error="connection reset""#, text(Level::Info, "Updated", &fields()));
+        assert_eq!("Warn: Retrying", text(Level::Warn, "Retrying", &Fields::default()));
+    }
+
+    #[test]
+    fn test_json() {
+        let line: Value = serde_json::from_str(&json(Level::Error, "Upsert error", &fields(), "2020-01-01T00:00:00.000Z")).unwrap();
+        assert_eq!("error", line["level"]);
+        assert_eq!("Upsert error", line["message"]);
+        assert_eq!(42, line["seqnum"]);
+        assert_eq!(120, line["duration_ms"]);
+        assert!(line.get("user_id").is_none());
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(Level::Debug, Level::from_str("DEBUG").unwrap());
+        assert!(Level::Warn < Level::Info);
+        assert!(Level::from_str("trace").is_err());
+        assert_eq!(Format::Json, Format::from_str("json").unwrap());
+    }
+}
@@ -0,0 +1,256 @@
+use hyper::StatusCode;
+use reqwest::Method;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+
+use health::Health;
+use log::{self, Fields};
+use sync::{SyncOk, SyncError};
+
+// Upper bounds in seconds of the d2l request latency histogram buckets.
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+lazy_static! {
+    /// Metrics of the running process, exposed by serve.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: [u64; 10],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS.iter()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters and gauges describing the progress of the sync
+/// rendered within the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    upserts: Mutex<BTreeMap<&'static str, u64>>,
+    /// Errors by variant and status code.
+    errors: Mutex<BTreeMap<(&'static str, String), u64>>,
+    /// Requests held back by the rate limit or by d2l, by reason.
+    throttled: Mutex<BTreeMap<&'static str, u64>>,
+    /// Latency of d2l requests by method, route, and status code.
+    requests: Mutex<BTreeMap<(String, String, String), Histogram>>,
+    /// Journal sequence number processed so far and the latest
+    /// sequence number within the source.
+    journal: Mutex<(Option<usize>, Option<usize>)>,
+    /// Nothing is recorded once disabled, such as during a dry run.
+    disabled: AtomicBool,
+}
+
+// Replace the ids within a request path so each route is a single series.
+fn route(path: &str) -> String {
+    path.split('/')
+        .map(|s| if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) { ":id" } else { s })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+impl Metrics {
+    /// Stop recording so a dry run does not report changes it never made.
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::Relaxed);
+    }
+
+    fn enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Count the outcome of syncing a user.
+    pub fn sync(&self, result: &Result<SyncOk, SyncError>) {
+        if !self.enabled() {
+            return;
+        }
+        match result {
+            Ok(ok) => *self.upserts.lock().unwrap().entry(ok.outcome()).or_insert(0) += 1,
+            Err(e) => self.error(e),
+        }
+    }
+
+    pub fn error(&self, err: &SyncError) {
+        if !self.enabled() {
+            return;
+        }
+        let status = err.status().map(|s| s.as_u16().to_string()).unwrap_or_default();
+        *self.errors.lock().unwrap().entry((err.variant(), status)).or_insert(0) += 1;
+    }
+
+    /// Count a request held back, see ThrottleCounts for the reasons.
+    pub fn throttled(&self, reason: &'static str) {
+        if !self.enabled() {
+            return;
+        }
+        *self.throttled.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    /// Observe the latency of a d2l request, the status is None when no response was received.
+    pub fn request(&self, method: &Method, path: &str, status: Option<StatusCode>, elapsed: Duration) {
+        if !self.enabled() {
+            return;
+        }
+        let status = status.map(|s| s.as_u16().to_string()).unwrap_or_default();
+        self.requests.lock().unwrap()
+            .entry((method.to_string(), route(path), status))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn journal(&self, seqnum: usize, max_id: Option<usize>) {
+        if !self.enabled() {
+            return;
+        }
+        let mut journal = self.journal.lock().unwrap();
+        journal.0 = Some(seqnum);
+        if max_id.is_some() {
+            journal.1 = max_id;
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP d2l_sync_upserts_total Users synced by outcome.\n");
+        out.push_str("# TYPE d2l_sync_upserts_total counter\n");
+        for (outcome, count) in self.upserts.lock().unwrap().iter() {
+            let _ = writeln!(out, "d2l_sync_upserts_total{{outcome=\"{}\"}} {}", outcome, count);
+        }
+        out.push_str("# HELP d2l_sync_errors_total Sync errors by variant and d2l status code.\n");
+        out.push_str("# TYPE d2l_sync_errors_total counter\n");
+        for ((variant, status), count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "d2l_sync_errors_total{{variant=\"{}\",status=\"{}\"}} {}", variant, status, count);
+        }
+        out.push_str("# HELP d2l_sync_throttled_total Requests held back by the rate limit or by d2l.\n");
+        out.push_str("# TYPE d2l_sync_throttled_total counter\n");
+        for (reason, count) in self.throttled.lock().unwrap().iter() {
+            let _ = writeln!(out, "d2l_sync_throttled_total{{reason=\"{}\"}} {}", reason, count);
+        }
+        out.push_str("# HELP d2l_sync_request_duration_seconds Latency of requests to d2l.\n");
+        out.push_str("# TYPE d2l_sync_request_duration_seconds histogram\n");
+        for ((method, route, status), histogram) in self.requests.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",route=\"{}\",status=\"{}\"", method, route, status);
+            for (count, bound) in histogram.counts.iter().zip(BUCKETS.iter()) {
+                let _ = writeln!(out, "d2l_sync_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "d2l_sync_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+            let _ = writeln!(out, "d2l_sync_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "d2l_sync_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+        let (seqnum, max_id) = *self.journal.lock().unwrap();
+        if let Some(seqnum) = seqnum {
+            out.push_str("# HELP d2l_sync_journal_seqnum Journal sequence number processed so far.\n");
+            out.push_str("# TYPE d2l_sync_journal_seqnum gauge\n");
+            let _ = writeln!(out, "d2l_sync_journal_seqnum {}", seqnum);
+            if let Some(max_id) = max_id {
+                out.push_str("# HELP d2l_sync_journal_lag Journal events not yet processed.\n");
+                out.push_str("# TYPE d2l_sync_journal_lag gauge\n");
+                let _ = writeln!(out, "d2l_sync_journal_lag {}", max_id.saturating_sub(seqnum));
+            }
+        }
+        out
+    }
+}
+
+/// Listen on addr, such as "0.0.0.0:9090", serving the metrics at /metrics
+/// along with the /healthz and /readyz endpoints.
+pub fn serve(addr: &str, health: Arc<Health>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            // Each request has its own thread so a readiness probe
+            // waiting on d2l never holds up a metrics scrape.
+            let health = health.clone();
+            thread::spawn(move || {
+                let result = stream.and_then(|stream| {
+                    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+                    respond(stream, &health)
+                });
+                if let Err(e) = result {
+                    log::warn("Metrics request failed", Fields { error: Some(e.to_string()), ..Fields::default() });
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, health: &Health) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, the request line is all that matters
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let text = "text/plain; version=0.0.4";
+    let json = "application/json";
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (StatusCode::OK, text, METRICS.render()),
+        (Some("GET"), Some("/healthz")) => {
+            let (status, body) = health.healthz(Utc::now().timestamp());
+            (status, json, body)
+        },
+        (Some("GET"), Some("/readyz")) => {
+            let (status, body) = health.readyz(Utc::now().timestamp());
+            (status, json, body)
+        },
+        (Some("GET"), _) => (StatusCode::NOT_FOUND, text, "Not Found\n".to_string()),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, text, "Method Not Allowed\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(lp_path!("/enrollments/orgUnits/:id/users/:id"), route(lp_path!("/enrollments/orgUnits/6606/users/42")));
+
+        let metrics = Metrics::default();
+        metrics.sync(&Ok(SyncOk::Created));
+        metrics.sync(&Ok(SyncOk::Created));
+        metrics.sync(&Err(SyncError::StatusCode(StatusCode::NOT_FOUND)));
+        metrics.request(&Method::GET, lp_path!("/users/42"), Some(StatusCode::OK), Duration::from_millis(300));
+        metrics.journal(90, Some(100));
+        metrics.throttled("too_many_requests");
+        let out = metrics.render();
+        assert!(out.contains("d2l_sync_upserts_total{outcome=\"created\"} 2\n"));
+        assert!(out.contains("d2l_sync_errors_total{variant=\"StatusCode\",status=\"404\"} 1\n"));
+        let labels = format!("method=\"GET\",route=\"{}\",status=\"200\"", lp_path!("/users/:id"));
+        assert!(out.contains(&format!("d2l_sync_request_duration_seconds_bucket{{{},le=\"0.25\"}} 0\n", labels)));
+        assert!(out.contains(&format!("d2l_sync_request_duration_seconds_bucket{{{},le=\"0.5\"}} 1\n", labels)));
+        assert!(out.contains("d2l_sync_throttled_total{reason=\"too_many_requests\"} 1\n"));
+        assert!(out.contains("d2l_sync_journal_seqnum 90\n"));
+        assert!(out.contains("d2l_sync_journal_lag 10\n"));
+
+        metrics.disable();
+        metrics.sync(&Ok(SyncOk::Created));
+        metrics.journal(95, Some(100));
+        assert_eq!(out, metrics.render());
+    }
+}
@@ -0,0 +1,143 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use schemas::ParseError;
+
+// Query and form parameters whose values are credentials: the d2l user id
+// and the app and user signatures of ID/key authentication, along with
+// the OAuth 2.0 tokens and client secret.
+const SECRET_PARAMS: [&str; 6] = ["x_b", "x_c", "x_d", "access_token", "refresh_token", "client_secret"];
+
+/// Whether personally identifiable information is written to the logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pii {
+    Show,
+    /// Replace each value with a stable hash.
+    Mask,
+}
+
+impl FromStr for Pii {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Show" => Ok(Pii::Show),
+            "Mask" => Ok(Pii::Mask),
+            _ => Err(ParseError::new(format!("Unknown PII mode {:?}, expected Show or Mask", s))),
+        }
+    }
+}
+
+// Whether values are masked along with the optional key of their hash,
+// values are shown until init is called with the configured mode.
+static SETTINGS: OnceLock<(Pii, Option<Vec<u8>>)> = OnceLock::new();
+
+/// Set whether user names, emails, names, and ids are masked within the
+/// logs along with a key for the hash so masked values may not be
+/// recovered by hashing likely values; only the first call has any effect.
+pub fn init(mode: Pii, key: Option<Vec<u8>>) {
+    let _ = SETTINGS.set((mode, key));
+}
+
+/// Stands in for a secret within Debug output.
+pub struct Secret;
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"[REDACTED]\"")
+    }
+}
+
+// Replace the value of each of the parameters found within text.
+fn params<F: Fn(&str) -> String>(text: &str, names: &[&str], replace: F) -> String {
+    let mut out = text.to_string();
+    for name in names {
+        let pattern = format!("{}=", name);
+        let mut start = 0;
+        while let Some(pos) = out[start..].find(&pattern) {
+            let begin = start + pos + pattern.len();
+            // Only match whole parameter names, not the tail of another
+            let whole = start + pos == 0 || !out[..start + pos].ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+            let end = out[begin..].find(|c: char| c == '&' || c == '"' || c == '\'' || c == ')' || c.is_whitespace())
+                .map_or(out.len(), |i| begin + i);
+            if whole {
+                let value = replace(&out[begin..end]);
+                out.replace_range(begin..end, &value);
+                start = begin + value.len();
+            } else {
+                start = end;
+            }
+        }
+    }
+    out
+}
+
+/// Replace the values of credential parameters found within text such
+/// as the Debug output of an error holding a signed request uri.
+pub fn secrets(text: &str) -> String {
+    params(text, &SECRET_PARAMS, |_| "[REDACTED]".to_string())
+}
+
+/// The text of an error as it may be written to the logs, without
+/// credentials and with the user name of a request uri masked.
+pub fn error(text: &str) -> String {
+    params(&secrets(text), &["userName"], |v| pii(v).into_owned())
+}
+
+// Hex of the first 6 bytes of the, optionally keyed, SHA-256 of the value.
+fn hash(key: Option<&[u8]>, value: &str) -> String {
+    let digest = match key {
+        Some(key) => {
+            let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC can take key of any size");
+            mac.input(value.as_bytes());
+            mac.result().code().to_vec()
+        },
+        None => Sha256::digest(value.as_bytes()).to_vec(),
+    };
+    digest.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
+fn mask<'a>(mode: Pii, key: Option<&[u8]>, value: &'a str) -> Cow<'a, str> {
+    match mode {
+        Pii::Show => Cow::Borrowed(value),
+        Pii::Mask => Cow::Owned(format!("masked:{}", hash(key, value))),
+    }
+}
+
+/// The value as it may be written to the logs, masked when the mode is Mask.
+/// The same value always masks to the same hash so entries may be correlated.
+pub fn pii(value: &str) -> Cow<'_, str> {
+    match SETTINGS.get() {
+        Some((mode, key)) => mask(*mode, key.as_deref(), value),
+        None => Cow::Borrowed(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets() {
+        let uri = r#"Http(Inner { url: Some("https://d2l/users/?&userName=jdoe&x_a=app&x_c=c1gn&x_b=usr&x_d=d1gn&x_t=1") })"#;
+        assert_eq!(r#"Http(Inner { url: Some("https://d2l/users/?&userName=jdoe&x_a=app&x_c=[REDACTED]&x_b=[REDACTED]&x_d=[REDACTED]&x_t=1") })"#, secrets(uri));
+        assert_eq!("refresh_token=[REDACTED]&my_access_token=kept", secrets("refresh_token=abc&my_access_token=kept"));
+        assert_eq!(r#""[REDACTED]""#, format!("{:?}", Secret));
+        assert_eq!("?&userName=jdoe&x_c=[REDACTED]", error("?&userName=jdoe&x_c=sig"));
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!("jdoe@txstate.edu", mask(Pii::Show, None, "jdoe@txstate.edu"));
+        let masked = mask(Pii::Mask, None, "jdoe@txstate.edu");
+        assert_eq!(masked, mask(Pii::Mask, None, "jdoe@txstate.edu"));
+        assert!(masked.starts_with("masked:") && masked.len() == 19);
+        assert!(!masked.contains("jdoe"));
+        assert_ne!(masked, mask(Pii::Mask, Some(b"key"), "jdoe@txstate.edu"));
+        assert_ne!(masked, mask(Pii::Mask, None, "jane@txstate.edu"));
+    }
+}
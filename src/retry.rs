@@ -0,0 +1,70 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use sync::SyncError;
+use log::{self, Fields};
+
+/// Retry transient sync errors, doubling the delay
+/// between each attempt up to the number of attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Backoff {
+    pub fn run<T, F>(&self, mut f: F) -> Result<T, SyncError>
+        where F: FnMut() -> Result<T, SyncError>
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(ref e) if e.is_transient() && attempt < self.attempts => {
+                    let wait = self.delay * 2u32.pow(attempt - 1);
+                    log::warn(&format!("Transient error on attempt {}, retrying in {:?}", attempt, wait), Fields {
+                        status: e.status().map(|s| s.as_u16()),
+                        error: Some(format!("{:?}", e)),
+                        ..Fields::default()
+                    });
+                    sleep(wait);
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::StatusCode;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff { attempts: 3, delay: Duration::from_millis(1) };
+
+        let mut calls = 0;
+        let result: Result<(), SyncError> = backoff.run(|| {
+            calls += 1;
+            Err(SyncError::StatusCode(StatusCode::BAD_GATEWAY))
+        });
+        assert!(result.is_err());
+        assert_eq!(3, calls);
+
+        let mut calls = 0;
+        let result: Result<(), SyncError> = backoff.run(|| {
+            calls += 1;
+            Err(SyncError::StatusCode(StatusCode::BAD_REQUEST))
+        });
+        assert!(result.is_err());
+        assert_eq!(1, calls);
+
+        let mut calls = 0;
+        let result = backoff.run(|| {
+            calls += 1;
+            if calls < 2 { Err(SyncError::StatusCode(StatusCode::SERVICE_UNAVAILABLE)) } else { Ok(calls) }
+        });
+        assert_eq!(2, result.unwrap());
+    }
+}
@@ -16,6 +16,9 @@
 //                                  #    even though we use it as a unique identifier)
 //   "ExternalEmail": String|null,  # Use alias for user's email (This field is used in group
 //                                  #    searches that match exactly)
+//   "Pronouns": String|null,       # Optional, only sent when provided by the source
+//   "PreferredFirstName": String|null,
+//   "PreferredLastName": String|null,
 //
 //   "Activation": {"IsActive": true},
 //
@@ -51,45 +54,124 @@
 //
 //   "Activation": {"IsActive": true}
 // }
+//
+// --- Get/Update User Attributes
+// {
+//   "UserId": usize,
+//   "Attributes": [{"AttributeId": String, "Value": [String]}]
+// }
 
 use std::str::FromStr;
+use std::fmt;
+use std::sync::RwLock;
+use std::collections::BTreeMap;
+
+use redact;
 
 #[derive(Debug)]
 pub struct ParseError {
-    err: &'static str,
+    err: String,
+}
+
+impl ParseError {
+    pub fn new<S: Into<String>>(err: S) -> ParseError {
+        ParseError{err: err.into()}
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.err)
+    }
 }
 
-// "109" = Faculty, "118" = Staff, "110" = Student
-#[derive(Clone, Copy, Debug)]
-pub enum Role {
-    Faculty,
-    Staff,
-    Student,
+lazy_static! {
+    /// Role names mapped to their d2l RoleId. Defaults to
+    /// "109" = Faculty, "118" = Staff, "110" = Student
+    /// until configured or discovered at startup.
+    static ref ROLES: RwLock<BTreeMap<String, String>> = {
+        let mut roles = BTreeMap::new();
+        roles.insert("Faculty".to_string(), "109".to_string());
+        roles.insert("Staff".to_string(), "118".to_string());
+        roles.insert("Student".to_string(), "110".to_string());
+        RwLock::new(roles)
+    };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Role {
+    name: String,
+    id: String,
 }
 
 impl Role {
     pub fn id(&self) -> &str {
-        match self {
-            Role::Faculty => "109",
-            Role::Staff => "118",
-            Role::Student => "110",
+        &self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Replace the role catalog with a comma delimited
+    /// list of Name=RoleId pairs such as "TA=111,Guest=112".
+    pub fn configure(catalog: &str) -> Result<(), ParseError> {
+        *ROLES.write().unwrap() = parse_catalog(catalog)?;
+        Ok(())
+    }
+
+    /// Add a role to the catalog unless the name is already configured.
+    pub fn register(name: &str, id: &str) {
+        register(&mut ROLES.write().unwrap(), name, id);
+    }
+}
+
+fn register(roles: &mut BTreeMap<String, String>, name: &str, id: &str) {
+    roles.entry(name.to_string()).or_insert_with(|| id.to_string());
+}
+
+// Look up a role by name within the catalog.
+fn lookup(roles: &BTreeMap<String, String>, s: &str) -> Result<Role, ParseError> {
+    match roles.get(s) {
+        Some(id) => Ok(Role{name: s.to_string(), id: id.clone()}),
+        None => {
+            let known: Vec<&str> = roles.keys().map(String::as_str).collect();
+            Err(ParseError::new(format!("Unknown role {:?}, expected one of {}", s, known.join(", "))))
+        },
+    }
+}
+
+/// Parse a comma delimited list of Name=RoleId pairs.
+pub fn parse_catalog(catalog: &str) -> Result<BTreeMap<String, String>, ParseError> {
+    let mut roles = BTreeMap::new();
+    for pair in catalog.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut fields = pair.splitn(2, '=').map(str::trim);
+        match (fields.next(), fields.next()) {
+            (Some(name), Some(id)) if !name.is_empty() && id.parse::<usize>().is_ok() => {
+                roles.insert(name.to_string(), id.to_string());
+            },
+            _ => return Err(ParseError::new(format!("Invalid role catalog entry {:?}", pair))),
         }
     }
+    Ok(roles)
 }
 
 impl FromStr for Role {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Faculty" => Ok(Role::Faculty),
-            "Staff" => Ok(Role::Staff),
-            "Student" => Ok(Role::Student),
-            _ => Err(ParseError{err: "Invalid Role type"}),
-        }
+        lookup(&ROLES.read().unwrap(), s)
     }
 }
 
+// Role(GET Method) as listed by d2l
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct RoleData {
+    pub identifier: String,
+    pub display_name: String,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct Activation {
@@ -105,6 +187,73 @@ pub struct UserBase {
     pub user_name: String,
     pub org_defined_id: Option<String>,
     pub external_email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pronouns: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_first_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_last_name: Option<String>,
+    /// Org-defined user attributes by AttributeId, synced through
+    /// the user attributes api rather than with the user data.
+    #[serde(skip)]
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl UserBase {
+    /// List the fields which differ from the desired user_base.
+    pub fn diff(&self, desired: &UserBase) -> Vec<Change> {
+        let mut changes = Vec::new();
+        Change::push(&mut changes, "FirstName", Some(&self.first_name), Some(&desired.first_name));
+        Change::push(&mut changes, "MiddleName", self.middle_name.as_ref(), desired.middle_name.as_ref());
+        Change::push(&mut changes, "LastName", Some(&self.last_name), Some(&desired.last_name));
+        Change::push(&mut changes, "UserName", Some(&self.user_name), Some(&desired.user_name));
+        Change::push(&mut changes, "OrgDefinedId", self.org_defined_id.as_ref(), desired.org_defined_id.as_ref());
+        Change::push(&mut changes, "ExternalEmail", self.external_email.as_ref(), desired.external_email.as_ref());
+        // Only compared when the source provides them so they are not cleared
+        if desired.pronouns.is_some() {
+            Change::push(&mut changes, "Pronouns", self.pronouns.as_ref(), desired.pronouns.as_ref());
+        }
+        if desired.preferred_first_name.is_some() {
+            Change::push(&mut changes, "PreferredFirstName", self.preferred_first_name.as_ref(), desired.preferred_first_name.as_ref());
+        }
+        if desired.preferred_last_name.is_some() {
+            Change::push(&mut changes, "PreferredLastName", self.preferred_last_name.as_ref(), desired.preferred_last_name.as_ref());
+        }
+        changes
+    }
+}
+
+/// A single field whose old value differs from the new value.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Change {
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Change {
+    pub fn push(changes: &mut Vec<Change>, field: &str, old: Option<&String>, new: Option<&String>) {
+        if old != new {
+            changes.push(Change {
+                field: field.to_string(),
+                old: old.cloned(),
+                new: new.cloned(),
+            });
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Every field other than the activation and role describes the user
+        let pii = !matches!(self.field.as_str(), "Activation.IsActive" | "RoleId");
+        let value = |v: &Option<String>| match v {
+            Some(v) if pii => format!("{:?}", redact::pii(v)),
+            Some(v) => format!("{:?}", v),
+            None => "null".to_string(),
+        };
+        write!(f, "{} changed from {} to {}", self.field, value(&self.old), value(&self.new))
+    }
 }
 
 // Read(GET Method) or Update(POST Method) User
@@ -118,6 +267,22 @@ pub struct UserReadOrUpdate {
     pub activation: Activation,
 }
 
+impl UserReadOrUpdate {
+    /// List the changes required to bring this account in
+    /// line with the desired user_base as an active user.
+    pub fn diff(&self, desired: &UserBase) -> Vec<Change> {
+        let mut changes = self.user_base.diff(desired);
+        if !self.activation.is_active {
+            changes.push(Change {
+                field: "Activation.IsActive".to_string(),
+                old: Some("false".to_string()),
+                new: Some("true".to_string()),
+            });
+        }
+        changes
+    }
+}
+
 // Create(POST Method) User
 #[derive(Serialize, PartialEq, Debug)]
 #[serde(rename_all = "PascalCase")]
@@ -129,6 +294,75 @@ pub struct UserCreate {
     pub send_creation_email: bool,
 }
 
+// Read(GET Method) or Update(PUT Method) User Attributes
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct UserAttributes {
+    pub user_id: usize,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct Attribute {
+    pub attribute_id: String,
+    pub value: Vec<String>,
+}
+
+impl UserAttributes {
+    /// List the attributes which differ from the desired
+    /// values, where multiple values are joined by a comma.
+    pub fn diff(&self, desired: &BTreeMap<String, String>) -> Vec<Change> {
+        let mut changes = Vec::new();
+        for (id, value) in desired {
+            let current = self.attributes.iter()
+                .find(|a| &a.attribute_id == id)
+                .map(|a| a.value.join(","));
+            Change::push(&mut changes, &format!("Attributes.{}", id), current.as_ref(), Some(value));
+        }
+        changes
+    }
+}
+
+// Read(GET Method) or Create/Update(POST Method) Enrollment
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct Enrollment {
+    pub org_unit_id: usize,
+    pub user_id: usize,
+    pub role_id: usize,
+}
+
+// Organization(GET Method) info of the org root
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct OrgInfo {
+    pub identifier: String,
+}
+
+// WhoAmI(GET Method) the account the requests are made as
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct WhoAmI {
+    pub identifier: String,
+    pub unique_name: String,
+}
+
+// Paged list of results such as users(GET Method)
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PagedResultSet<T> {
+    pub paging_info: PagingInfo,
+    pub items: Vec<T>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PagingInfo {
+    pub bookmark: Option<String>,
+    pub has_more_items: bool,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,6 +380,7 @@ mod tests {
                 user_name: "j_d1".to_string(),
                 org_defined_id: Some("A00000000".to_string()),
                 external_email: Some("jdoe@txstate.edu".to_string()),
+                ..UserBase::default()
             },
             user_id: 100,
             activation: Activation{is_active: true},
@@ -163,6 +398,7 @@ mod tests {
                 user_name: "j_d1".to_string(),
                 org_defined_id: Some("A00000000".to_string()),
                 external_email: Some("jdoe@txstate.edu".to_string()),
+                ..UserBase::default()
             },
             user_id: 100,
             activation: Activation{is_active: true},
@@ -172,6 +408,50 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_read_update_extended() {
+        let data = r#"{"FirstName":"John","MiddleName":"","LastName":"Doe","UserName":"j_d1","OrgDefinedId":"A00000000","ExternalEmail":"jdoe@txstate.edu","Pronouns":"he/him","PreferredFirstName":"Jack","PreferredLastName":null,"OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#;
+        let actual: UserReadOrUpdate = serde_json::from_str(data).unwrap();
+        let expected = UserReadOrUpdate {
+            user_base: UserBase {
+                first_name: "John".to_string(),
+                middle_name: Some("".to_string()),
+                last_name: "Doe".to_string(),
+                user_name: "j_d1".to_string(),
+                org_defined_id: Some("A00000000".to_string()),
+                external_email: Some("jdoe@txstate.edu".to_string()),
+                pronouns: Some("he/him".to_string()),
+                preferred_first_name: Some("Jack".to_string()),
+                preferred_last_name: None,
+                attributes: BTreeMap::new(),
+            },
+            user_id: 100,
+            activation: Activation{is_active: true},
+        };
+        assert_eq!(expected, actual);
+
+        let expected = r#"{"FirstName":"John","MiddleName":"","LastName":"Doe","UserName":"j_d1","OrgDefinedId":"A00000000","ExternalEmail":"jdoe@txstate.edu","Pronouns":"he/him","PreferredFirstName":"Jack","Activation":{"IsActive":true}}"#;
+        assert_eq!(expected, serde_json::to_string(&actual).unwrap());
+    }
+
+    #[test]
+    fn test_attributes() {
+        let data = r#"{"UserId":100,"Attributes":[{"AttributeId":"college","Value":["Science"]},{"AttributeId":"major","Value":["Biology","Chemistry"]}]}"#;
+        let actual: UserAttributes = serde_json::from_str(data).unwrap();
+        assert_eq!(data, serde_json::to_string(&actual).unwrap());
+
+        let mut desired = BTreeMap::new();
+        desired.insert("college".to_string(), "Science".to_string());
+        desired.insert("major".to_string(), "Biology".to_string());
+        desired.insert("minor".to_string(), "Math".to_string());
+        let changes: Vec<String> = actual.diff(&desired).iter().map(|c| c.to_string()).collect();
+        let expected = vec![
+            r#"Attributes.major changed from "Biology,Chemistry" to "Biology""#.to_string(),
+            r#"Attributes.minor changed from null to "Math""#.to_string(),
+        ];
+        assert_eq!(expected, changes);
+    }
+
     #[test]
     fn test_create() {
         let data = UserCreate {
@@ -182,8 +462,9 @@ mod tests {
                 user_name: "j_d1".to_string(),
                 org_defined_id: Some("A00000000".to_string()),
                 external_email: Some("jdoe@txstate.edu".to_string()),
+                ..UserBase::default()
             },
-            role_id: Role::Faculty.id().to_string(),
+            role_id: Role::from_str("Faculty").unwrap().id().to_string(),
             is_active: true,
             send_creation_email: false,
         };
@@ -191,4 +472,142 @@ mod tests {
         let actual = serde_json::to_string(&data).unwrap();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_enrollment() {
+        let data = Enrollment {
+            org_unit_id: 6606,
+            user_id: 100,
+            role_id: Role::from_str("Student").unwrap().id().parse().unwrap(),
+        };
+        let expected = r#"{"OrgUnitId":6606,"UserId":100,"RoleId":110}"#;
+        let actual = serde_json::to_string(&data).unwrap();
+        assert_eq!(expected, actual);
+
+        let read = r#"{"OrgUnitId":6606,"UserId":100,"RoleId":110,"IsCascading":false}"#;
+        let actual: Enrollment = serde_json::from_str(read).unwrap();
+        assert_eq!(data, actual);
+    }
+
+    #[test]
+    fn test_role() {
+        // A local catalog so the global one other tests rely on is untouched
+        let mut roles = parse_catalog("Faculty=109,Student=110").unwrap();
+        register(&mut roles, "TA", "111");
+        register(&mut roles, "Student", "999");
+        assert_eq!("111", lookup(&roles, "TA").unwrap().id());
+        assert_eq!("110", lookup(&roles, "Student").unwrap().id());
+        assert_eq!("Unknown role \"Guest\", expected one of Faculty, Student, TA", lookup(&roles, "Guest").unwrap_err().err);
+        assert_eq!("110", Role::from_str("Student").unwrap().id());
+    }
+
+    #[test]
+    fn test_parse_catalog() {
+        let roles = parse_catalog("Faculty=109, TA = 111,").unwrap();
+        assert_eq!(Some(&"109".to_string()), roles.get("Faculty"));
+        assert_eq!(Some(&"111".to_string()), roles.get("TA"));
+        assert_eq!(2, roles.len());
+        assert!(parse_catalog("Faculty").is_err());
+        assert!(parse_catalog("=109").is_err());
+        assert!(parse_catalog("TA=ta").is_err());
+    }
+
+    #[test]
+    fn test_role_data() {
+        let data = r#"[{"Identifier":"109","DisplayName":"Instructor","Code":null}]"#;
+        let actual: Vec<RoleData> = serde_json::from_str(data).unwrap();
+        let expected = vec![RoleData {
+            identifier: "109".to_string(),
+            display_name: "Instructor".to_string(),
+        }];
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_diff() {
+        let current = UserReadOrUpdate {
+            user_base: UserBase {
+                first_name: "John".to_string(),
+                middle_name: Some("".to_string()),
+                last_name: "Doe".to_string(),
+                user_name: "j_d1".to_string(),
+                org_defined_id: Some("A00000000".to_string()),
+                external_email: None,
+                ..UserBase::default()
+            },
+            user_id: 100,
+            activation: Activation{is_active: false},
+        };
+        let desired = UserBase {
+            first_name: "John".to_string(),
+            middle_name: Some("".to_string()),
+            last_name: "Smith".to_string(),
+            user_name: "j_d1".to_string(),
+            org_defined_id: Some("A00000000".to_string()),
+            external_email: Some("jdoe@txstate.edu".to_string()),
+            ..UserBase::default()
+        };
+        let actual: Vec<String> = current.diff(&desired).iter().map(|c| c.to_string()).collect();
+        let expected = vec![
+            r#"LastName changed from "Doe" to "Smith""#.to_string(),
+            r#"ExternalEmail changed from null to "jdoe@txstate.edu""#.to_string(),
+            r#"Activation.IsActive changed from "false" to "true""#.to_string(),
+        ];
+        assert_eq!(expected, actual);
+        assert!(current.user_base.diff(&current.user_base).is_empty());
+    }
+
+    #[test]
+    fn test_diff_extended() {
+        let desired = UserBase {
+            first_name: "John".to_string(),
+            middle_name: Some("".to_string()),
+            last_name: "Doe".to_string(),
+            user_name: "j_d1".to_string(),
+            org_defined_id: Some("A00000000".to_string()),
+            external_email: Some("jdoe@txstate.edu".to_string()),
+            ..UserBase::default()
+        };
+
+        // A response without the pronoun or preferred name fields
+        let data = r#"{"FirstName":"John","MiddleName":"","LastName":"Doe","UserName":"j_d1","OrgDefinedId":"A00000000","ExternalEmail":"jdoe@txstate.edu","OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#;
+        let current: UserReadOrUpdate = serde_json::from_str(data).unwrap();
+        assert!(current.diff(&desired).is_empty());
+
+        // A response which carries them, as null when unset
+        let desired = UserBase {
+            pronouns: Some("he/him".to_string()),
+            preferred_first_name: Some("Jack".to_string()),
+            ..desired
+        };
+        let data = r#"{"FirstName":"John","MiddleName":"","LastName":"Doe","UserName":"j_d1","OrgDefinedId":"A00000000","ExternalEmail":"jdoe@txstate.edu","Pronouns":"he/him","PreferredFirstName":"Jack","PreferredLastName":null,"OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#;
+        let current: UserReadOrUpdate = serde_json::from_str(data).unwrap();
+        assert!(current.diff(&desired).is_empty());
+    }
+
+    #[test]
+    fn test_paged() {
+        let data = r#"{"PagingInfo":{"Bookmark":"100","HasMoreItems":true},"Items":[{"FirstName":"John","MiddleName":"","LastName":"Doe","UserName":"j_d1","OrgDefinedId":"A00000000","ExternalEmail":"jdoe@txstate.edu","OrgId":6606,"UserId":100,"Activation":{"IsActive":true},"DisplayName":"John Doe","UniqueIdentifier":"j_d1@txstate.edu"}]}"#;
+        let actual: PagedResultSet<UserReadOrUpdate> = serde_json::from_str(data).unwrap();
+        let expected = PagedResultSet {
+            paging_info: PagingInfo {
+                bookmark: Some("100".to_string()),
+                has_more_items: true,
+            },
+            items: vec![UserReadOrUpdate {
+                user_base: UserBase {
+                    first_name: "John".to_string(),
+                    middle_name: Some("".to_string()),
+                    last_name: "Doe".to_string(),
+                    user_name: "j_d1".to_string(),
+                    org_defined_id: Some("A00000000".to_string()),
+                    external_email: Some("jdoe@txstate.edu".to_string()),
+                    ..UserBase::default()
+                },
+                user_id: 100,
+                activation: Activation{is_active: true},
+            }],
+        };
+        assert_eq!(expected, actual);
+    }
 }
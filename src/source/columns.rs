@@ -0,0 +1,240 @@
+use schemas::{UserBase, Role, ParseError};
+use std::str::FromStr;
+
+use super::{SourceError, user_base};
+use super::names::{self, Names, NameRules};
+
+/// The column names and text values of a single row.
+pub type Columns = Vec<(String, Option<String>)>;
+
+/// Adjustment applied to a column value before it is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    Trim,
+    Lower,
+    Upper,
+    /// An empty value is treated as null.
+    NullEmpty,
+}
+
+impl FromStr for Transform {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trim" => Ok(Transform::Trim),
+            "lower" => Ok(Transform::Lower),
+            "upper" => Ok(Transform::Upper),
+            "nullempty" => Ok(Transform::NullEmpty),
+            _ => Err(ParseError::new(format!("Unknown column transform {:?}", s))),
+        }
+    }
+}
+
+impl Transform {
+    fn apply(self, value: Option<String>) -> Option<String> {
+        let value = value?;
+        match self {
+            Transform::Trim => Some(value.trim().to_string()),
+            Transform::Lower => Some(value.to_lowercase()),
+            Transform::Upper => Some(value.to_uppercase()),
+            Transform::NullEmpty => if value.is_empty() { None } else { Some(value) },
+        }
+    }
+}
+
+/// Where a field is found within the row of the user query.
+#[derive(Clone, Debug, PartialEq)]
+enum Column {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    column: Column,
+    transforms: Vec<Transform>,
+}
+
+impl Field {
+    fn new(column: Column) -> Field {
+        Field {
+            column,
+            transforms: Vec::new(),
+        }
+    }
+
+    fn value(&self, row: &Columns) -> Option<Option<String>> {
+        let value = match self.column {
+            Column::Index(idx) => row.get(idx),
+            // Unquoted aliases may be folded to lower case by the database
+            Column::Name(ref name) => row.iter().find(|&(c, _)| c.eq_ignore_ascii_case(name)),
+        }?.1.clone();
+        Some(self.transforms.iter().fold(value, |value, t| t.apply(value)))
+    }
+}
+
+const FIELDS: [&str; 11] = ["PreferredName", "FirstName", "MiddleName", "LastName", "UserName", "OrgDefinedId", "ExternalEmail", "Role", "PreferredLastName", "Consent", "Pronouns"];
+
+// Fields which may be null or missing from the user query.
+const OPTIONAL: [&str; 5] = ["PreferredName", "MiddleName", "PreferredLastName", "Consent", "Pronouns"];
+
+/// Parse a comma delimited list of AttributeId=column pairs.
+pub fn parse_attributes(s: &str) -> Result<Vec<(String, String)>, ParseError> {
+    let mut attrs = Vec::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let mut kv = pair.splitn(2, '=').map(str::trim);
+        match (kv.next(), kv.next()) {
+            (Some(id), Some(column)) if !id.is_empty() && !column.is_empty() => attrs.push((id.to_string(), column.to_string())),
+            _ => return Err(ParseError::new(format!("Invalid attribute mapping {:?}", pair))),
+        }
+    }
+    Ok(attrs)
+}
+
+/// Maps the columns of the user query onto the user fields. By
+/// default columns are read by position in the order of FIELDS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserColumns {
+    fields: Vec<Field>,
+}
+
+impl Default for UserColumns {
+    fn default() -> UserColumns {
+        UserColumns {
+            fields: (0..FIELDS.len()).map(|idx| Field::new(Column::Index(idx))).collect(),
+        }
+    }
+}
+
+// Parse a comma delimited list of field=column pairs each followed by
+// any number of |transform, for example "ExternalEmail=email|trim|lower".
+// Every field not listed is read from the column named after the field.
+impl FromStr for UserColumns {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields: Vec<Field> = FIELDS.iter().map(|f| Field::new(Column::Name(f.to_string()))).collect();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=').map(str::trim);
+            let (name, mapping) = match (kv.next(), kv.next()) {
+                (Some(name), Some(mapping)) => (name, mapping),
+                _ => return Err(ParseError::new(format!("Invalid column mapping {:?}", pair))),
+            };
+            let idx = FIELDS.iter().position(|&f| f == name)
+                .ok_or_else(|| ParseError::new(format!("Unknown column mapping field {:?}, expected one of {}", name, FIELDS.join(", "))))?;
+            let mut parts = mapping.split('|').map(str::trim);
+            let column = match parts.next() {
+                Some(column) if !column.is_empty() => column.to_string(),
+                _ => return Err(ParseError::new(format!("Invalid column mapping {:?}", pair))),
+            };
+            fields[idx] = Field {
+                column: Column::Name(column),
+                transforms: parts.map(Transform::from_str).collect::<Result<_, _>>()?,
+            };
+        }
+        Ok(UserColumns { fields })
+    }
+}
+
+impl UserColumns {
+    /// Check the column names of the user query provide every required field.
+    pub fn validate(&self, columns: &[String]) -> Result<(), ParseError> {
+        let mut missing = Vec::new();
+        for (field, name) in self.fields.iter().zip(FIELDS.iter()) {
+            let found = match field.column {
+                Column::Index(idx) => idx < columns.len(),
+                Column::Name(ref column) => columns.iter().any(|c| c.eq_ignore_ascii_case(column)),
+            };
+            if !found && !OPTIONAL.contains(name) {
+                missing.push(match field.column {
+                    Column::Index(idx) => format!("{} (column {})", name, idx + 1),
+                    Column::Name(ref column) => format!("{} ({})", name, column),
+                });
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(ParseError::new(format!("User query is missing columns for {}", missing.join(", "))))
+        }
+    }
+
+    fn get(&self, row: &Columns, idx: usize) -> Option<String> {
+        self.fields[idx].value(row).unwrap_or(None)
+    }
+
+    fn required(&self, row: &Columns, idx: usize) -> Result<String, SourceError> {
+        self.get(row, idx).ok_or_else(|| ParseError::new(format!("Missing {} value", FIELDS[idx])).into())
+    }
+
+    /// Build the role and user_base from a row of the user query along
+    /// with the d2l attributes found within the columns they map to.
+    pub fn user(&self, row: &Columns, attributes: &[(String, String)], rules: &NameRules) -> Result<(Role, UserBase), SourceError> {
+        let role = self.required(row, 7)?;
+        let names = Names {
+            preferred_first: self.get(row, 0),
+            first: self.required(row, 1)?,
+            middle: self.get(row, 2),
+            preferred_last: self.get(row, 8),
+            last: self.required(row, 3)?,
+            consent: names::consent(self.get(row, 9)),
+        };
+        let mut user_base = user_base(rules, names, self.required(row, 4)?, self.required(row, 5)?, self.required(row, 6)?);
+        user_base.pronouns = self.get(row, 10);
+        for (id, column) in attributes {
+            let value = row.iter().find(|&(c, _)| c.eq_ignore_ascii_case(column)).and_then(|c| c.1.clone());
+            if let Some(value) = value {
+                user_base.attributes.insert(id.clone(), value);
+            }
+        }
+        Ok((Role::from_str(&role)?, user_base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(columns: &[(&str, Option<&str>)]) -> Columns {
+        columns.iter().map(|&(c, v)| (c.to_string(), v.map(str::to_string))).collect()
+    }
+
+    #[test]
+    fn test_user_columns() {
+        let columns = UserColumns::from_str("FirstName=first|trim, ExternalEmail=email|trim|lower, PreferredName=nick|nullempty").unwrap();
+        let data = row(&[
+            ("affiliation", Some("Faculty")), ("email", Some(" JDoe@txstate.edu ")), ("first", Some(" John ")), ("nick", Some("")),
+            ("LastName", Some("Doe")), ("USERNAME", Some("j_d1")), ("OrgDefinedId", Some("X00000000")),
+        ]);
+        assert!(columns.validate(&data.iter().map(|c| c.0.clone()).collect::<Vec<_>>()).is_err());
+        let columns = UserColumns::from_str("FirstName=first|trim,ExternalEmail=email|trim|lower,PreferredName=nick|nullempty,Role=affiliation").unwrap();
+        assert!(columns.validate(&data.iter().map(|c| c.0.clone()).collect::<Vec<_>>()).is_ok());
+        let (role, user_base) = columns.user(&data, &[], &NameRules::default()).unwrap();
+        assert_eq!("Faculty", role.name());
+        assert_eq!("John", user_base.first_name);
+        assert_eq!(Some("".to_string()), user_base.middle_name);
+        assert_eq!("j_d1", user_base.user_name);
+        assert_eq!(Some("jdoe@txstate.edu".to_string()), user_base.external_email);
+
+        assert!(UserColumns::from_str("Nickname=nick").is_err());
+        assert_eq!(vec![("college".to_string(), "college_code".to_string())], parse_attributes("college = college_code,").unwrap());
+        assert!(parse_attributes("college").is_err());
+        assert!(UserColumns::from_str("FirstName=first|capitalize").is_err());
+    }
+
+    #[test]
+    fn test_user_columns_positional() {
+        let columns = UserColumns::default();
+        let data = row(&[
+            ("a", Some("Johnny")), ("b", Some("John")), ("c", None), ("d", Some("Doe")),
+            ("e", Some("j_d1")), ("f", Some("X00000000")), ("g", Some("jdoe@txstate.edu")), ("h", Some("Student")),
+        ]);
+        assert!(columns.validate(&data.iter().map(|c| c.0.clone()).collect::<Vec<_>>()).is_ok());
+        assert!(columns.validate(&data[..7].iter().map(|c| c.0.clone()).collect::<Vec<_>>()).is_err());
+        let (role, user_base) = columns.user(&data, &[], &NameRules::default()).unwrap();
+        assert_eq!("Student", role.name());
+        assert_eq!("Johnny", user_base.first_name);
+        assert!(columns.user(&data[..7].to_vec(), &[], &NameRules::default()).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use schemas::{UserBase, Role};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+
+use super::SourceError;
+
+/// A row of a batch file: the UserBase fields along with an
+/// optional Role which otherwise defaults to the --role option.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Record {
+    #[serde(flatten)]
+    user_base: UserBase,
+    role: Option<String>,
+}
+
+/// A parsed row or the reason it could not be parsed.
+pub type Row = Result<(Role, UserBase), SourceError>;
+
+/// Load every row of a CSV (.csv) or JSON Lines file where each row
+/// either parses into the role and user_base or records its error.
+pub fn load(path: &str, role: &Role) -> Result<Vec<Row>, SourceError> {
+    let file = File::open(path)?;
+    if path.to_lowercase().ends_with(".csv") {
+        csv(file, role)
+    } else {
+        json_lines(BufReader::new(file), role)
+    }
+}
+
+// CSV fields are kept as strings, so an OrgDefinedId of digits is not
+// mistaken for a number, and then parsed the same as a JSON line.
+fn csv<R: Read>(reader: R, role: &Role) -> Result<Vec<Row>, SourceError> {
+    let mut reader = ::csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let mut rows = Vec::new();
+    for row in reader.records() {
+        rows.push(row.map_err(SourceError::from).and_then(|row| {
+            let fields = headers.iter().zip(row.iter())
+                .map(|(h, v)| (h.to_string(), ::serde_json::Value::String(v.to_string())))
+                .collect();
+            record(::serde_json::from_value(::serde_json::Value::Object(fields))?, role)
+        }));
+    }
+    Ok(rows)
+}
+
+fn json_lines<R: BufRead>(reader: R, role: &Role) -> Result<Vec<Row>, SourceError> {
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        rows.push(::serde_json::from_str(&line).map_err(SourceError::from).and_then(|r| record(r, role)));
+    }
+    Ok(rows)
+}
+
+fn record(record: Record, role: &Role) -> Result<(Role, UserBase), SourceError> {
+    let mut user_base = record.user_base;
+    if user_base.middle_name.is_none() {
+        // Default to empty string according to D2L Schema
+        user_base.middle_name = Some("".to_string());
+    }
+    match record.role {
+        Some(ref r) if !r.is_empty() => Ok((Role::from_str(r)?, user_base)),
+        _ => Ok((role.clone(), user_base)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv() {
+        let data = "FirstName,MiddleName,LastName,UserName,OrgDefinedId,ExternalEmail,Role\n\
+            John,,Doe,j_d1,00000001,jdoe@txstate.edu,Faculty\n\
+            Jane,,Doe,j_d2,00000002,jane@txstate.edu,\n\
+            Jim,,Doe,j_d3,00000003,jim@txstate.edu,Janitor\n";
+        let rows = csv(data.as_bytes(), &Role::from_str("Student").unwrap()).unwrap();
+        assert_eq!(3, rows.len());
+        let (role, user_base) = rows[0].as_ref().unwrap();
+        assert_eq!("Faculty", role.name());
+        assert_eq!(Some("00000001".to_string()), user_base.org_defined_id);
+        assert_eq!(Some("".to_string()), user_base.middle_name);
+        assert_eq!("Student", rows[1].as_ref().unwrap().0.name());
+        assert!(rows[2].is_err());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let data = "{\"FirstName\":\"John\",\"LastName\":\"Doe\",\"UserName\":\"j_d1\",\"OrgDefinedId\":\"X00000000\",\"ExternalEmail\":\"jdoe@txstate.edu\",\"Role\":\"Staff\"}\n\
+            \n\
+            {\"FirstName\":\"Jane\"}\n";
+        let rows = json_lines(data.as_bytes(), &Role::from_str("Student").unwrap()).unwrap();
+        assert_eq!(2, rows.len());
+        let (role, user_base) = rows[0].as_ref().unwrap();
+        assert_eq!("Staff", role.name());
+        assert_eq!("j_d1", user_base.user_name);
+        assert!(rows[1].is_err());
+    }
+}
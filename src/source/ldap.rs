@@ -0,0 +1,386 @@
+use schemas::{UserBase, Role, ParseError};
+use ldap3::{LdapConn, LdapError, Scope, SearchEntry};
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use redact;
+use super::{Source, SourceError, Settings, Event, user_base};
+use super::names::{self, Names};
+
+/// Names of the LDAP attributes holding each user field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attributes {
+    id: String,
+    preferred_name: Option<String>,
+    first_name: String,
+    middle_name: Option<String>,
+    last_name: String,
+    user_name: String,
+    org_defined_id: String,
+    external_email: String,
+    role: String,
+    preferred_last_name: Option<String>,
+    consent: Option<String>,
+    pronouns: Option<String>,
+}
+
+impl Default for Attributes {
+    fn default() -> Attributes {
+        Attributes {
+            id: "uidNumber".to_string(),
+            preferred_name: None,
+            first_name: "givenName".to_string(),
+            middle_name: None,
+            last_name: "sn".to_string(),
+            user_name: "uid".to_string(),
+            org_defined_id: "employeeID".to_string(),
+            external_email: "mail".to_string(),
+            role: "employeeType".to_string(),
+            preferred_last_name: None,
+            consent: None,
+            pronouns: None,
+        }
+    }
+}
+
+// Parse a comma delimited list of field=attribute pairs, such as
+// "UserName=sAMAccountName,Role=eduPersonPrimaryAffiliation", where
+// any field not listed keeps its default attribute.
+impl FromStr for Attributes {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut attrs = Attributes::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=').map(str::trim);
+            let (field, attr) = match (kv.next(), kv.next()) {
+                (Some(field), Some(attr)) if !attr.is_empty() => (field, attr.to_string()),
+                _ => return Err(ParseError::new(format!("Invalid attribute mapping {:?}", pair))),
+            };
+            match field {
+                "Id" => attrs.id = attr,
+                "PreferredName" => attrs.preferred_name = Some(attr),
+                "FirstName" => attrs.first_name = attr,
+                "MiddleName" => attrs.middle_name = Some(attr),
+                "LastName" => attrs.last_name = attr,
+                "UserName" => attrs.user_name = attr,
+                "OrgDefinedId" => attrs.org_defined_id = attr,
+                "ExternalEmail" => attrs.external_email = attr,
+                "Role" => attrs.role = attr,
+                "PreferredLastName" => attrs.preferred_last_name = Some(attr),
+                "Consent" => attrs.consent = Some(attr),
+                "Pronouns" => attrs.pronouns = Some(attr),
+                _ => return Err(ParseError::new(format!("Unknown attribute mapping field {:?}", field))),
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+impl Attributes {
+    // Every attribute of a user along with those of the d2l attributes.
+    fn all<'a>(&'a self, user_attributes: &'a [(String, String)]) -> Vec<&'a str> {
+        let mut all = vec![&self.id, &self.first_name, &self.last_name, &self.user_name, &self.org_defined_id, &self.external_email, &self.role];
+        all.extend(self.preferred_name.iter());
+        all.extend(self.middle_name.iter());
+        all.extend(self.preferred_last_name.iter());
+        all.extend(self.consent.iter());
+        all.extend(self.pronouns.iter());
+        all.extend(user_attributes.iter().map(|a| &a.1));
+        all.into_iter().map(String::as_str).collect()
+    }
+}
+
+/// Attribute used as the journal sequence number to detect changed entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Cursor {
+    /// Generalized time such as 20181018123045Z with the digits as the sequence number.
+    #[default]
+    ModifyTimestamp,
+    /// Active Directory update sequence number.
+    USNChanged,
+}
+
+impl FromStr for Cursor {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modifyTimestamp" => Ok(Cursor::ModifyTimestamp),
+            "uSNChanged" => Ok(Cursor::USNChanged),
+            _ => Err(ParseError::new("Invalid LDAP cursor")),
+        }
+    }
+}
+
+impl Cursor {
+    fn attr(self) -> &'static str {
+        match self {
+            Cursor::ModifyTimestamp => "modifyTimestamp",
+            Cursor::USNChanged => "uSNChanged",
+        }
+    }
+
+    fn seqnum(self, value: &str) -> Option<usize> {
+        match self {
+            Cursor::ModifyTimestamp => value.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok(),
+            Cursor::USNChanged => value.parse().ok(),
+        }
+    }
+
+    fn value(self, seqnum: usize) -> String {
+        match self {
+            Cursor::ModifyTimestamp => format!("{}Z", seqnum),
+            Cursor::USNChanged => seqnum.to_string(),
+        }
+    }
+}
+
+// Entries requested per page, below the MaxPageSize of 1000 Active Directory defaults to.
+const PAGE_SIZE: i32 = 500;
+
+/// LDAP directory source where each entry selected by the filter is a user.
+/// There is no journal so the cursor attribute of each entry is used instead.
+pub struct Ldap {
+    uri: String,
+    /// Dropped once the server closes it and established again on next use.
+    conn: Mutex<Option<LdapConn>>,
+    settings: Settings,
+    /// Sequence number of the last journal batch along with the ids returned
+    /// at it, so entries sharing it are not returned again.
+    seen: Mutex<(usize, BTreeSet<usize>)>,
+}
+
+impl Ldap {
+    pub fn new(uri: &str, settings: Settings) -> Result<Ldap, SourceError> {
+        let ldap = Ldap {
+            uri: uri.to_string(),
+            conn: Mutex::new(None),
+            settings,
+            seen: Mutex::new((0, BTreeSet::new())),
+        };
+        *ldap.conn.lock().unwrap() = Some(ldap.connect()?);
+        Ok(ldap)
+    }
+
+    fn connect(&self) -> Result<LdapConn, LdapError> {
+        let mut conn = LdapConn::new(&self.uri)?;
+        if let Some(ref bind_dn) = self.settings.ldap_bind_dn {
+            conn.simple_bind(bind_dn, &self.settings.ldap_bind_password)?.success()?;
+        }
+        Ok(conn)
+    }
+
+    // Run an operation on the connection, connecting again and retrying
+    // once when the server has dropped it, such as after a restart.
+    fn with_conn<T, F>(&self, mut f: F) -> Result<T, SourceError>
+        where F: FnMut(&mut LdapConn) -> Result<T, LdapError>
+    {
+        let mut conn = self.conn.lock().unwrap();
+        let mut retried = false;
+        loop {
+            if conn.as_mut().map_or(true, LdapConn::is_closed) {
+                *conn = Some(self.connect()?);
+                retried = true;
+            }
+            match f(conn.as_mut().expect("Connected above")) {
+                Err(ref e) if !retried && dropped(e) => *conn = None,
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    // Every entry below the base, requested a page at a time so directories
+    // limiting the entries returned by a single search are read in full.
+    fn search(&self, filter: &str, attrs: Vec<&str>) -> Result<Vec<SearchEntry>, SourceError> {
+        let base = &self.settings.ldap_base_dn;
+        self.with_conn(|conn| {
+            let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![Box::new(EntriesOnly::new()), Box::new(PagedResults::new(PAGE_SIZE))];
+            let mut stream = conn.streaming_search_with(adapters, base, Scope::Subtree, filter, attrs.clone())?;
+            let mut entries = Vec::new();
+            while let Some(entry) = stream.next()? {
+                entries.push(SearchEntry::construct(entry));
+            }
+            stream.result().success()?;
+            Ok(entries)
+        })
+    }
+
+    fn find(&self, user: usize, attrs: Vec<&str>) -> Result<Option<SearchEntry>, SourceError> {
+        let filter = format!("(&{}({}={}))", self.settings.ldap_filter, self.settings.ldap_attributes.id, user);
+        Ok(self.search(&filter, attrs)?.into_iter().next())
+    }
+
+    // Sequence numbers of every entry selected by the filter.
+    fn events(&self, filter: &str) -> Result<Vec<Event>, SourceError> {
+        let attrs = &self.settings.ldap_attributes;
+        let cursor = self.settings.ldap_cursor;
+        let mut events = Vec::new();
+        for entry in self.search(filter, vec![attrs.id.as_str(), cursor.attr()])? {
+            let sn = attr(&entry, cursor.attr()).and_then(|v| cursor.seqnum(&v));
+            let id = attr(&entry, &attrs.id).and_then(|v| v.parse().ok());
+            events.push((sn, id));
+        }
+        events.sort();
+        Ok(events)
+    }
+}
+
+// First value of an attribute, names are matched without regard to case.
+fn attr(entry: &SearchEntry, name: &str) -> Option<String> {
+    entry.attrs.iter()
+        .find(|&(k, _)| k.eq_ignore_ascii_case(name))
+        .and_then(|(_, v)| v.first().cloned())
+}
+
+// Errors raised once the connection to the server is gone.
+fn dropped(err: &LdapError) -> bool {
+    matches!(err, LdapError::Io { .. } | LdapError::OpSend { .. } | LdapError::ResultRecv { .. } | LdapError::EndOfStream)
+}
+
+// Drop the events at the start which were already returned, as LDAP filters only
+// offer >= and entries changed later within the same second share the start.
+fn unseen(events: Vec<Event>, start: usize, seen: &(usize, BTreeSet<usize>)) -> Vec<Event> {
+    events.into_iter()
+        .filter(|e| match *e {
+            (Some(sn), _) if sn > start => true,
+            (Some(sn), Some(id)) if sn == start => seen.0 != start || !seen.1.contains(&id),
+            (Some(sn), None) => sn == start && seen.0 != start,
+            _ => false,
+        })
+        .collect()
+}
+
+// The DN names the person, so it is masked along with the other PII.
+fn required(entry: &SearchEntry, name: &str) -> Result<String, SourceError> {
+    attr(entry, name).ok_or_else(|| ParseError::new(format!("Missing attribute {} of {}", name, redact::pii(&entry.dn))).into())
+}
+
+// Keep the first limit events, along with any that share the last sequence
+// number, as entries changed within the same second must not be skipped.
+fn truncate(events: &mut Vec<Event>, limit: usize) {
+    if events.len() > limit && limit > 0 {
+        let last = events[limit - 1].0;
+        let keep = limit + events[limit..].iter().take_while(|e| e.0 == last).count();
+        events.truncate(keep);
+    }
+}
+
+impl Source for Ldap {
+    // Read the base entry alone, asking for no attributes
+    fn ping(&self) -> Result<(), SourceError> {
+        self.with_conn(|conn| conn.search(&self.settings.ldap_base_dn, Scope::Base, "(objectClass=*)", vec!["1.1"])?.success().map(|_| ()))
+    }
+
+    fn journal_max_id(&self) -> Result<Option<usize>, SourceError> {
+        Ok(self.events(&self.settings.ldap_filter)?.into_iter().filter_map(|e| e.0).max())
+    }
+
+    fn journal(&self, start: usize, limit: usize) -> Result<Option<Vec<Event>>, SourceError> {
+        let cursor = self.settings.ldap_cursor;
+        let filter = format!("(&{}({}>={}))", self.settings.ldap_filter, cursor.attr(), cursor.value(start));
+        let mut seen = self.seen.lock().unwrap();
+        let mut events = unseen(self.events(&filter)?, start, &seen);
+        truncate(&mut events, limit);
+        // Remember the ids at the sequence number the journal moves on to
+        let last = match events.last() {
+            Some(&(Some(last), _)) => last,
+            _ => return Ok(None),
+        };
+        if seen.0 != last {
+            *seen = (last, BTreeSet::new());
+        }
+        seen.1.extend(events.iter().filter(|e| e.0 == Some(last)).filter_map(|e| e.1));
+        Ok(Some(events))
+    }
+
+    fn user(&self, user: usize) -> Result<Option<(Role, UserBase)>, SourceError> {
+        let attrs = &self.settings.ldap_attributes;
+        match self.find(user, attrs.all(&self.settings.user_attributes))? {
+            Some(entry) => {
+                let role = required(&entry, &attrs.role)?;
+                let names = Names {
+                    preferred_first: attrs.preferred_name.as_ref().and_then(|a| attr(&entry, a)),
+                    first: required(&entry, &attrs.first_name)?,
+                    middle: attrs.middle_name.as_ref().and_then(|a| attr(&entry, a)),
+                    preferred_last: attrs.preferred_last_name.as_ref().and_then(|a| attr(&entry, a)),
+                    last: required(&entry, &attrs.last_name)?,
+                    consent: names::consent(attrs.consent.as_ref().and_then(|a| attr(&entry, a))),
+                };
+                let mut user_base = user_base(&self.settings.name_rules, names, required(&entry, &attrs.user_name)?,
+                    required(&entry, &attrs.org_defined_id)?, required(&entry, &attrs.external_email)?);
+                user_base.pronouns = attrs.pronouns.as_ref().and_then(|a| attr(&entry, a));
+                for (id, name) in &self.settings.user_attributes {
+                    if let Some(value) = attr(&entry, name) {
+                        user_base.attributes.insert(id.clone(), value);
+                    }
+                }
+                Ok(Some((Role::from_str(&role)?, user_base)))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn user_ids(&self) -> Result<Vec<usize>, SourceError> {
+        Ok(self.events(&self.settings.ldap_filter)?.into_iter().filter_map(|e| e.1).collect())
+    }
+
+    // Looked up without the filter so users which no longer match it may be
+    // deprovisioned, however entries removed from the directory are not found.
+    fn user_name(&self, user: usize) -> Result<Option<String>, SourceError> {
+        let attrs = &self.settings.ldap_attributes;
+        let filter = format!("({}={})", attrs.id, user);
+        match self.search(&filter, vec![attrs.user_name.as_str()])?.into_iter().next() {
+            Some(entry) => Ok(attr(&entry, &attrs.user_name)),
+            None => Ok(None),
+        }
+    }
+
+    // Enrollments are not held within the directory.
+    fn enrollments(&self, _user: usize) -> Result<Vec<(usize, Role, bool)>, SourceError> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes() {
+        let attrs = Attributes::from_str("UserName=sAMAccountName, PreferredName=displayName,Role=eduPersonPrimaryAffiliation").unwrap();
+        assert_eq!("sAMAccountName", attrs.user_name);
+        assert_eq!(Some("displayName".to_string()), attrs.preferred_name);
+        assert_eq!("eduPersonPrimaryAffiliation", attrs.role);
+        assert_eq!("givenName", attrs.first_name);
+        assert!(Attributes::from_str("Nickname=cn").is_err());
+        assert!(Attributes::from_str("UserName=").is_err());
+    }
+
+    #[test]
+    fn test_cursor() {
+        assert_eq!(Some(20181018123045), Cursor::ModifyTimestamp.seqnum("20181018123045Z"));
+        assert_eq!(Some(20181018123045), Cursor::ModifyTimestamp.seqnum("20181018123045.0Z"));
+        assert_eq!("20181018123045Z", Cursor::ModifyTimestamp.value(20181018123045));
+        assert_eq!(Some(12345), Cursor::USNChanged.seqnum("12345"));
+
+        let mut events = vec![(Some(1), Some(10)), (Some(2), Some(11)), (Some(2), Some(12)), (Some(3), Some(13))];
+        truncate(&mut events, 2);
+        assert_eq!(vec![(Some(1), Some(10)), (Some(2), Some(11)), (Some(2), Some(12))], events);
+    }
+
+    #[test]
+    fn test_unseen() {
+        let events = vec![(Some(5), Some(10)), (Some(5), Some(11)), (Some(6), Some(12)), (None, Some(13))];
+        // Only the entries already returned at the start are dropped
+        let mut seen = (5, BTreeSet::new());
+        seen.1.insert(10);
+        assert_eq!(vec![(Some(5), Some(11)), (Some(6), Some(12))], unseen(events.clone(), 5, &seen));
+        // Nothing is known of the start after a restart so each is synced again
+        let seen = (0, BTreeSet::new());
+        assert_eq!(vec![(Some(5), Some(10)), (Some(5), Some(11)), (Some(6), Some(12))], unseen(events.clone(), 5, &seen));
+        assert_eq!(vec![(Some(6), Some(12))], unseen(events, 6, &seen));
+    }
+}
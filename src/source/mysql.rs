@@ -0,0 +1,108 @@
+use schemas::{UserBase, Role};
+use mysql::{self as my, Pool};
+use std::str::FromStr;
+
+use schemas::ParseError;
+use super::{Source, SourceError, Settings, Event, Columns};
+
+pub struct MySql {
+    pool: Pool,
+    settings: Settings,
+}
+
+impl MySql {
+    pub fn new(uri: &str, settings: Settings) -> Result<MySql, SourceError> {
+        Ok(MySql{
+            pool: Pool::new(uri)?,
+            settings,
+        })
+    }
+}
+
+// Text of a column value where numbers are accepted as well as strings.
+fn text(value: my::Value) -> Result<Option<String>, SourceError> {
+    match value {
+        my::Value::NULL => Ok(None),
+        my::Value::Bytes(bytes) => Ok(Some(String::from_utf8_lossy(&bytes).into_owned())),
+        my::Value::Int(i) => Ok(Some(i.to_string())),
+        my::Value::UInt(i) => Ok(Some(i.to_string())),
+        value => Err(ParseError::new(format!("Unsupported column value {:?}", value)).into()),
+    }
+}
+
+impl Source for MySql {
+    fn ping(&self) -> Result<(), SourceError> {
+        self.pool.prep_exec("SELECT 1", ())?;
+        Ok(())
+    }
+
+    fn journal_max_id(&self) -> Result<Option<usize>, SourceError> {
+        let mut query_journal_max_id = self.pool.prepare(self.settings.query_journal_max_id()?)?;
+        if let Some(row) = query_journal_max_id.execute(())?.next() {
+            return Ok(Some(my::from_row::<usize>(row?)));
+        }
+        Ok(None)
+    }
+
+    fn journal(&self, start: usize, limit: usize) -> Result<Option<Vec<Event>>, SourceError> {
+        let mut query_journal = self.pool.prepare(self.settings.query_journal()?)?;
+        let mut events = Vec::new();
+        for row in query_journal.execute((start, limit))? {
+            let (sn, id) = my::from_row::<(usize, Option<usize>)>(row?);
+            events.push((Some(sn), id));
+        }
+        if events.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(events))
+        }
+    }
+
+    fn user(&self, user: usize) -> Result<Option<(Role, UserBase)>, SourceError> {
+        let mut query_user = self.pool.prepare(self.settings.query_user()?)?;
+        let mut result = query_user.execute((user,))?;
+        let names: Vec<String> = result.columns_ref().iter().map(|c| c.name_str().into_owned()).collect();
+        if let Some(row) = result.next() {
+            let mut columns = Columns::new();
+            for (name, value) in names.into_iter().zip(row?.unwrap()) {
+                columns.push((name, text(value)?));
+            }
+            return Ok(Some(self.settings.user(&columns)?));
+        }
+        Ok(None)
+    }
+
+    fn user_columns(&self) -> Result<Option<Vec<String>>, SourceError> {
+        let query_user = self.pool.prepare(self.settings.query_user()?)?;
+        Ok(Some(query_user.columns_ref().unwrap_or(&[]).iter().map(|c| c.name_str().into_owned()).collect()))
+    }
+
+    fn user_ids(&self) -> Result<Vec<usize>, SourceError> {
+        let mut query_users = self.pool.prepare(self.settings.query_users()?)?;
+        let mut ids = Vec::new();
+        for row in query_users.execute(())? {
+            ids.push(my::from_row::<usize>(row?));
+        }
+        Ok(ids)
+    }
+
+    fn user_name(&self, user: usize) -> Result<Option<String>, SourceError> {
+        let mut query_user_name = self.pool.prepare(self.settings.query_user_name()?)?;
+        if let Some(row) = query_user_name.execute((user,))?.next() {
+            return Ok(Some(my::from_row::<String>(row?)));
+        }
+        Ok(None)
+    }
+
+    fn enrollments(&self, user: usize) -> Result<Vec<(usize, Role, bool)>, SourceError> {
+        let mut enrollments = Vec::new();
+        if let Some(ref query) = self.settings.query_enrollments {
+            let mut query_enrollments = self.pool.prepare(query)?;
+            for row in query_enrollments.execute((user,))? {
+                let (org_unit, role, enrolled) = my::from_row::<(usize, String, bool)>(row?);
+                enrollments.push((org_unit, Role::from_str(&role)?, enrolled));
+            }
+        }
+        Ok(enrollments)
+    }
+}
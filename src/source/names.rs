@@ -0,0 +1,151 @@
+use schemas::ParseError;
+use std::str::FromStr;
+
+/// The name columns of a user before any rules are applied.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Names {
+    pub preferred_first: Option<String>,
+    pub first: String,
+    pub middle: Option<String>,
+    pub preferred_last: Option<String>,
+    pub last: String,
+    /// Whether the user has agreed to their chosen name being used.
+    pub consent: bool,
+}
+
+/// Interpret a consent column such as 1, true, Y, or yes.
+pub fn consent(value: Option<String>) -> bool {
+    value.is_some_and(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "t" | "true" | "y" | "yes"))
+}
+
+/// Rules deciding which of a user's names are sent to d2l. With no rules
+/// a preferred first name replaces the first name and drops the middle name.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NameRules {
+    /// Keep the middle name alongside a preferred first name.
+    pub keep_middle: bool,
+    /// Use the preferred last name in place of the last name.
+    pub preferred_last: bool,
+    /// Only use preferred names when the user has consented.
+    pub consent: bool,
+    /// Never use preferred names.
+    pub legal: bool,
+    /// Send preferred names within d2l's own preferred name
+    /// fields rather than in place of the legal names.
+    pub preferred_fields: bool,
+}
+
+// Parse a comma delimited list of rule names such as "KeepMiddle,Consent".
+impl FromStr for NameRules {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = NameRules::default();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            match rule {
+                "KeepMiddle" => rules.keep_middle = true,
+                "PreferredLast" => rules.preferred_last = true,
+                "Consent" => rules.consent = true,
+                "Legal" => rules.legal = true,
+                "PreferredFields" => rules.preferred_fields = true,
+                _ => return Err(ParseError::new(format!("Unknown name rule {:?}, expected one of KeepMiddle, PreferredLast, Consent, Legal, PreferredFields", rule))),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+impl NameRules {
+    // Whether the user's chosen names may be used at all.
+    fn chosen(&self, names: &Names) -> bool {
+        !self.legal && (!self.consent || names.consent)
+    }
+
+    /// Returns the preferred first and last names for d2l's preferred name fields.
+    pub fn preferred(&self, names: &Names) -> (Option<String>, Option<String>) {
+        if self.preferred_fields && self.chosen(names) {
+            (names.preferred_first.clone(), names.preferred_last.clone())
+        } else {
+            (None, None)
+        }
+    }
+
+    /// Returns the first, middle, and last names.
+    pub fn apply(&self, names: Names) -> (String, Option<String>, String) {
+        let chosen = self.chosen(&names) && !self.preferred_fields;
+        // Default to empty string according to D2L Schema
+        let middle = Some(names.middle.unwrap_or_default());
+        let (first, middle) = match names.preferred_first {
+            Some(preferred) if chosen => (preferred, if self.keep_middle { middle } else { None }),
+            _ => (names.first, middle),
+        };
+        let last = match names.preferred_last {
+            Some(preferred) if chosen && self.preferred_last => preferred,
+            _ => names.last,
+        };
+        (first, middle, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(consent: bool) -> Names {
+        Names {
+            preferred_first: Some("Jack".to_string()),
+            first: "John".to_string(),
+            middle: Some("Quincy".to_string()),
+            preferred_last: Some("Dee".to_string()),
+            last: "Doe".to_string(),
+            consent,
+        }
+    }
+
+    fn apply(rules: &str, names: Names) -> (String, Option<String>, String) {
+        NameRules::from_str(rules).unwrap().apply(names)
+    }
+
+    #[test]
+    fn test_default_rules() {
+        assert_eq!(("Jack".to_string(), None, "Doe".to_string()), apply("", names(false)));
+        let legal = Names { preferred_first: None, middle: None, ..names(false) };
+        assert_eq!(("John".to_string(), Some("".to_string()), "Doe".to_string()), apply("", legal));
+    }
+
+    #[test]
+    fn test_keep_middle() {
+        assert_eq!(("Jack".to_string(), Some("Quincy".to_string()), "Doe".to_string()), apply("KeepMiddle", names(false)));
+    }
+
+    #[test]
+    fn test_preferred_last() {
+        assert_eq!(("Jack".to_string(), None, "Dee".to_string()), apply("PreferredLast", names(false)));
+        let no_preferred = Names { preferred_last: None, ..names(false) };
+        assert_eq!(("Jack".to_string(), None, "Doe".to_string()), apply("PreferredLast", no_preferred));
+    }
+
+    #[test]
+    fn test_consent() {
+        assert_eq!(("John".to_string(), Some("Quincy".to_string()), "Doe".to_string()), apply("Consent,PreferredLast", names(false)));
+        assert_eq!(("Jack".to_string(), None, "Dee".to_string()), apply("Consent,PreferredLast", names(true)));
+        assert!(consent(Some(" Yes".to_string())));
+        assert!(!consent(Some("0".to_string())));
+        assert!(!consent(None));
+    }
+
+    #[test]
+    fn test_preferred_fields() {
+        let rules = NameRules::from_str("PreferredFields,Consent").unwrap();
+        assert_eq!((Some("Jack".to_string()), Some("Dee".to_string())), rules.preferred(&names(true)));
+        assert_eq!((None, None), rules.preferred(&names(false)));
+        assert_eq!(("John".to_string(), Some("Quincy".to_string()), "Doe".to_string()), rules.apply(names(true)));
+        assert_eq!((None, None), NameRules::default().preferred(&names(true)));
+    }
+
+    #[test]
+    fn test_legal() {
+        assert_eq!(("John".to_string(), Some("Quincy".to_string()), "Doe".to_string()), apply("Legal,PreferredLast", names(true)));
+        assert!(NameRules::from_str("Nickname").is_err());
+    }
+}
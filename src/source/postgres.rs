@@ -0,0 +1,244 @@
+use schemas::{UserBase, Role};
+use postgres::{Client, Config, NoTls, Row};
+use postgres::config::SslMode;
+use postgres::types::{ToSql, Type};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use schemas::ParseError;
+
+use super::{Source, SourceError, Settings, Event, Columns};
+
+/// PostgreSQL source where queries use $1, $2 style parameters.
+pub struct Postgres {
+    conn: Connection,
+    settings: Settings,
+}
+
+impl Postgres {
+    pub fn new(uri: &str, settings: Settings) -> Result<Postgres, SourceError> {
+        Ok(Postgres{
+            conn: Connection::new(uri)?,
+            settings,
+        })
+    }
+}
+
+/// A client which is connected again once the server has closed it,
+/// such as after a restart, shared with the checkpoint table.
+pub struct Connection {
+    config: Config,
+    client: Mutex<Client>,
+}
+
+impl Connection {
+    pub fn new(uri: &str) -> Result<Connection, SourceError> {
+        let config = Config::from_str(uri)?;
+        let client = connect(&config)?;
+        Ok(Connection {
+            config,
+            client: Mutex::new(client),
+        })
+    }
+
+    /// Run an operation on the client, connecting again and retrying once
+    /// when the connection was closed before or during the operation.
+    pub fn with<T, F>(&self, mut f: F) -> Result<T, SourceError>
+        where F: FnMut(&mut Client) -> Result<T, SourceError>
+    {
+        let mut client = self.client.lock().unwrap();
+        let mut retried = false;
+        loop {
+            if client.is_closed() {
+                *client = connect(&self.config)?;
+                retried = true;
+            }
+            match f(&mut client) {
+                Err(_) if !retried && client.is_closed() => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+// The sslmode of the uri decides whether TLS is used, where prefer and
+// require verify the server certificate against the system roots.
+fn connect(config: &Config) -> Result<Client, SourceError> {
+    match config.get_ssl_mode() {
+        SslMode::Disable => Ok(config.connect(NoTls)?),
+        _ => Ok(config.connect(MakeTlsConnector::new(TlsConnector::new()?))?),
+    }
+}
+
+// Ids may be integer or bigint columns.
+fn id(row: &Row, idx: usize) -> Result<Option<usize>, SourceError> {
+    let id = match row.try_get::<_, Option<i64>>(idx) {
+        Ok(id) => id,
+        Err(_) => row.try_get::<_, Option<i32>>(idx)?.map(i64::from),
+    };
+    match id {
+        Some(id) => usize::try_from(id).map(Some).map_err(|_| ParseError::new(format!("Invalid negative id {}", id)).into()),
+        None => Ok(None),
+    }
+}
+
+// Bind an id as the type the server inferred for the parameter, as a
+// parameter compared with an integer column is int4 rather than int8.
+fn param(ty: &Type, value: usize) -> Result<Box<dyn ToSql + Sync>, SourceError> {
+    let out_of_range = || ParseError::new(format!("Id {} is out of range for a {} parameter", value, ty));
+    match *ty {
+        Type::INT2 => Ok(Box::new(i16::try_from(value).map_err(|_| out_of_range())?)),
+        Type::INT4 => Ok(Box::new(i32::try_from(value).map_err(|_| out_of_range())?)),
+        _ => Ok(Box::new(i64::try_from(value).map_err(|_| out_of_range())?)),
+    }
+}
+
+impl Postgres {
+    fn query(&self, query: &str, values: &[usize]) -> Result<Vec<Row>, SourceError> {
+        self.conn.with(|client| {
+            let stmt = client.prepare(query)?;
+            let params = stmt.params().iter().zip(values)
+                .map(|(ty, &value)| param(ty, value))
+                .collect::<Result<Vec<_>, _>>()?;
+            let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| &**p).collect();
+            Ok(client.query(&stmt, &params)?)
+        })
+    }
+}
+
+// Text of a column where integer columns are accepted as well.
+fn text(row: &Row, idx: usize) -> Result<Option<String>, SourceError> {
+    match row.try_get::<_, Option<String>>(idx) {
+        Ok(text) => Ok(text),
+        Err(_) => Ok(id(row, idx)?.map(|id| id.to_string())),
+    }
+}
+
+impl Source for Postgres {
+    fn ping(&self) -> Result<(), SourceError> {
+        self.conn.with(|client| Ok(client.simple_query("SELECT 1").map(|_| ())?))
+    }
+
+    fn journal_max_id(&self) -> Result<Option<usize>, SourceError> {
+        let rows = self.query(self.settings.query_journal_max_id()?, &[])?;
+        match rows.first() {
+            Some(row) => id(row, 0),
+            None => Ok(None),
+        }
+    }
+
+    fn journal(&self, start: usize, limit: usize) -> Result<Option<Vec<Event>>, SourceError> {
+        let rows = self.query(self.settings.query_journal()?, &[start, limit])?;
+        let mut events = Vec::new();
+        for row in &rows {
+            events.push((id(row, 0)?, id(row, 1)?));
+        }
+        if events.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(events))
+        }
+    }
+
+    fn user(&self, user: usize) -> Result<Option<(Role, UserBase)>, SourceError> {
+        let rows = self.query(self.settings.query_user()?, &[user])?;
+        match rows.first() {
+            Some(row) => {
+                let mut columns = Columns::new();
+                for (idx, column) in row.columns().iter().enumerate() {
+                    columns.push((column.name().to_string(), text(row, idx)?));
+                }
+                Ok(Some(self.settings.user(&columns)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn user_columns(&self) -> Result<Option<Vec<String>>, SourceError> {
+        let query_user = self.conn.with(|client| Ok(client.prepare(self.settings.query_user()?)?))?;
+        Ok(Some(query_user.columns().iter().map(|c| c.name().to_string()).collect()))
+    }
+
+    fn user_ids(&self) -> Result<Vec<usize>, SourceError> {
+        let rows = self.query(self.settings.query_users()?, &[])?;
+        let mut ids = Vec::new();
+        for row in &rows {
+            ids.extend(id(row, 0)?);
+        }
+        Ok(ids)
+    }
+
+    fn user_name(&self, user: usize) -> Result<Option<String>, SourceError> {
+        let rows = self.query(self.settings.query_user_name()?, &[user])?;
+        match rows.first() {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn enrollments(&self, user: usize) -> Result<Vec<(usize, Role, bool)>, SourceError> {
+        let mut enrollments = Vec::new();
+        if let Some(ref query) = self.settings.query_enrollments {
+            let rows = self.query(query, &[user])?;
+            for row in &rows {
+                let role: String = row.try_get(1)?;
+                if let Some(org_unit) = id(row, 0)? {
+                    enrollments.push((org_unit, Role::from_str(&role)?, row.try_get(2)?));
+                }
+            }
+        }
+        Ok(enrollments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Run against a local container with D2L_TEST_POSTGRES set to its uri
+    // and cargo test -- --ignored, see the README.
+    #[test]
+    #[ignore]
+    fn test_postgres() {
+        let uri = env::var("D2L_TEST_POSTGRES").expect("D2L_TEST_POSTGRES is required");
+        let settings = Settings {
+            query_journal_max_id: Some("SELECT MAX(sn) FROM journal".to_string()),
+            query_journal: Some("SELECT sn, uid FROM journal WHERE sn > $1 ORDER BY sn LIMIT $2".to_string()),
+            query_user: Some("SELECT preferred, first, middle, last, \"user\", id, email, role FROM users WHERE uid = $1".to_string()),
+            query_user_name: Some("SELECT \"user\" FROM users WHERE uid = $1".to_string()),
+            query_users: Some("SELECT uid FROM users".to_string()),
+            query_enrollments: Some("SELECT org_unit, role, enrolled FROM enrollments WHERE uid = $1".to_string()),
+            ..Settings::default()
+        };
+        let db = Postgres::new(&uri, settings).unwrap();
+        // Temporary tables belong to the single connection of the source
+        db.conn.client.lock().unwrap().batch_execute("
+            CREATE TEMP TABLE journal (sn bigserial PRIMARY KEY, uid integer);
+            CREATE TEMP TABLE users (uid serial, preferred text, first text, middle text, last text, \"user\" text, id text, email text, role text);
+            CREATE TEMP TABLE enrollments (uid integer, org_unit integer, role text, enrolled boolean);
+            INSERT INTO journal (uid) VALUES (1), (2), (1);
+            INSERT INTO users (preferred, first, middle, last, \"user\", id, email, role) VALUES (NULL, 'John', NULL, 'Doe', 'j_d1', 'X00000000', 'jdoe@txstate.edu', 'Faculty');
+            INSERT INTO enrollments VALUES (1, 6606, 'Student', true);
+        ").unwrap();
+
+        assert!(db.ping().is_ok());
+        assert_eq!(8, db.user_columns().unwrap().unwrap().len());
+        assert_eq!(Some(3), db.journal_max_id().unwrap());
+        assert_eq!(Some(vec![(Some(2), Some(2)), (Some(3), Some(1))]), db.journal(1, 10).unwrap());
+        assert_eq!(vec![1], db.user_ids().unwrap());
+        let (role, user) = db.user(1).unwrap().unwrap();
+        assert_eq!("Faculty", role.name());
+        assert_eq!("j_d1", user.user_name);
+        assert!(db.user(2).unwrap().is_none());
+        assert_eq!(Some("j_d1".to_string()), db.user_name(1).unwrap());
+        assert_eq!(1, db.enrollments(1).unwrap().len());
+        assert!(db.user(usize::MAX).is_err());
+
+        db.conn.client.lock().unwrap().batch_execute("INSERT INTO journal (uid) VALUES (-1)").unwrap();
+        assert!(db.journal(3, 10).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use schemas::{UserBase, Role};
+use rusqlite::{Connection, Row};
+use rusqlite::types::ValueRef;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use schemas::ParseError;
+
+use super::{Source, SourceError, Settings, Event, Columns};
+
+/// SQLite source for local development where queries use ?1, ?2
+/// style parameters and the uri is "sqlite://path/to/file.db"
+pub struct Sqlite {
+    conn: Mutex<Connection>,
+    settings: Settings,
+}
+
+impl Sqlite {
+    pub fn new(uri: &str, settings: Settings) -> Result<Sqlite, SourceError> {
+        let path = uri.trim_start_matches("sqlite://");
+        Ok(Sqlite{
+            conn: Mutex::new(Connection::open(path)?),
+            settings,
+        })
+    }
+}
+
+fn id(row: &Row, idx: usize) -> Result<Option<usize>, SourceError> {
+    match row.get::<_, Option<i64>>(idx)? {
+        Some(id) => usize::try_from(id).map(Some).map_err(|_| ParseError::new(format!("Invalid negative id {}", id)).into()),
+        None => Ok(None),
+    }
+}
+
+// SQLite integers are signed 64 bit.
+fn param(value: usize) -> Result<i64, SourceError> {
+    i64::try_from(value).map_err(|_| ParseError::new(format!("Id {} is out of range for an integer parameter", value)).into())
+}
+
+// Text of a column where numbers are accepted as well as text.
+fn text(row: &Row, idx: usize) -> Result<Option<String>, SourceError> {
+    match row.get_ref(idx)? {
+        ValueRef::Null => Ok(None),
+        ValueRef::Integer(i) => Ok(Some(i.to_string())),
+        ValueRef::Real(f) => Ok(Some(f.to_string())),
+        ValueRef::Text(text) | ValueRef::Blob(text) => Ok(Some(String::from_utf8_lossy(text).into_owned())),
+    }
+}
+
+impl Source for Sqlite {
+    fn ping(&self) -> Result<(), SourceError> {
+        self.conn.lock().unwrap().query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn journal_max_id(&self) -> Result<Option<usize>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(self.settings.query_journal_max_id()?)?;
+        let mut rows = stmt.query([])?;
+        match rows.next()? {
+            Some(row) => id(row, 0),
+            None => Ok(None),
+        }
+    }
+
+    fn journal(&self, start: usize, limit: usize) -> Result<Option<Vec<Event>>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(self.settings.query_journal()?)?;
+        let mut rows = stmt.query([param(start)?, param(limit)?])?;
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            events.push((id(row, 0)?, id(row, 1)?));
+        }
+        if events.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(events))
+        }
+    }
+
+    fn user(&self, user: usize) -> Result<Option<(Role, UserBase)>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(self.settings.query_user()?)?;
+        let names: Vec<String> = stmt.column_names().into_iter().map(str::to_string).collect();
+        let mut rows = stmt.query([param(user)?])?;
+        match rows.next()? {
+            Some(row) => {
+                let mut columns = Columns::new();
+                for (idx, name) in names.into_iter().enumerate() {
+                    columns.push((name, text(row, idx)?));
+                }
+                Ok(Some(self.settings.user(&columns)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn user_columns(&self) -> Result<Option<Vec<String>>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let stmt = conn.prepare(self.settings.query_user()?)?;
+        Ok(Some(stmt.column_names().into_iter().map(str::to_string).collect()))
+    }
+
+    fn user_ids(&self) -> Result<Vec<usize>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(self.settings.query_users()?)?;
+        let mut rows = stmt.query([])?;
+        let mut ids = Vec::new();
+        while let Some(row) = rows.next()? {
+            ids.extend(id(row, 0)?);
+        }
+        Ok(ids)
+    }
+
+    fn user_name(&self, user: usize) -> Result<Option<String>, SourceError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(self.settings.query_user_name()?)?;
+        let mut rows = stmt.query([param(user)?])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn enrollments(&self, user: usize) -> Result<Vec<(usize, Role, bool)>, SourceError> {
+        let mut enrollments = Vec::new();
+        if let Some(ref query) = self.settings.query_enrollments {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(query)?;
+            let mut rows = stmt.query([param(user)?])?;
+            while let Some(row) = rows.next()? {
+                let role: String = row.get(1)?;
+                if let Some(org_unit) = id(row, 0)? {
+                    enrollments.push((org_unit, Role::from_str(&role)?, row.get(2)?));
+                }
+            }
+        }
+        Ok(enrollments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite() {
+        let settings = Settings {
+            query_journal_max_id: Some("SELECT MAX(sn) FROM journal".to_string()),
+            query_journal: Some("SELECT sn, uid FROM journal WHERE sn > ?1 ORDER BY sn LIMIT ?2".to_string()),
+            query_user: Some("SELECT preferred, first, middle, last, user, id, email, role FROM users WHERE uid = ?1".to_string()),
+            query_users: Some("SELECT uid FROM users".to_string()),
+            ..Settings::default()
+        };
+        let db = Sqlite::new("sqlite://:memory:", settings).unwrap();
+        db.conn.lock().unwrap().execute_batch("
+            CREATE TABLE journal (sn INTEGER PRIMARY KEY, uid INTEGER);
+            CREATE TABLE users (uid INTEGER, preferred TEXT, first TEXT, middle TEXT, last TEXT, user TEXT, id TEXT, email TEXT, role TEXT);
+            INSERT INTO journal VALUES (1, 42), (2, 43), (3, 42), (4, -1);
+            INSERT INTO users VALUES (42, NULL, 'John', NULL, 'Doe', 'j_d1', 'X00000000', 'jdoe@txstate.edu', 'Faculty');
+        ").unwrap();
+
+        assert!(db.ping().is_ok());
+        assert_eq!(8, db.user_columns().unwrap().unwrap().len());
+        assert_eq!(Some(4), db.journal_max_id().unwrap());
+        assert_eq!(Some(vec![(Some(2), Some(43)), (Some(3), Some(42))]), db.journal(1, 2).unwrap());
+        assert_eq!(None, db.journal(4, 10).unwrap());
+        // Negative ids are rejected rather than wrapping around
+        assert!(matches!(db.journal(3, 10), Err(SourceError::Parse(_))));
+        assert!(matches!(db.journal(usize::MAX, 10), Err(SourceError::Parse(_))));
+        assert_eq!(vec![42], db.user_ids().unwrap());
+        let (role, user) = db.user(42).unwrap().unwrap();
+        assert_eq!("Faculty", role.name());
+        assert_eq!("j_d1", user.user_name);
+        assert_eq!(Some("".to_string()), user.middle_name);
+        assert!(db.user(43).unwrap().is_none());
+        assert!(db.user_name(42).is_err());
+    }
+}
@@ -1,56 +1,188 @@
-use chrono::Utc;
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use base64::URL_SAFE_NO_PAD;
-
 use hyper::StatusCode;
-use reqwest::Client;
+use reqwest::{Client, Method, Response};
 use std::io::Read;
+use std::fmt;
+use std::thread::sleep;
+use std::time::Instant;
+use std::sync::Mutex;
 
-use schemas::{UserReadOrUpdate, UserCreate, Activation, UserBase, Role};
+use schemas::{UserReadOrUpdate, UserCreate, Activation, UserBase, Role, RoleData, PagedResultSet, Change, Enrollment, OrgInfo};
+use schemas::{UserAttributes, Attribute, WhoAmI};
+use deprovision::Policy;
+use throttle::Throttle;
+use auth::Authenticator;
+use metrics::METRICS;
+use log::{self, Fields};
+use redact;
 
-const USR_PATH: &str = r#"/d2l/api/lp/1.20/users/"#;
-const USR_QUERY: &str = r#"&userName="#;
+// Every route uses the same LP API version, one whose UserData
+// carries Pronouns, PreferredFirstName and PreferredLastName.
+macro_rules! lp_path {
+    ($path:expr) => { concat!("/d2l/api/lp/1.43", $path) };
+}
 
-type HmacSha256 = Hmac<Sha256>;
+const USR_PATH: &str = lp_path!("/users/");
+const USR_QUERY: &str = r#"&userName="#;
+const BOOKMARK_QUERY: &str = r#"&bookmark="#;
+const WHOAMI_PATH: &str = lp_path!("/users/whoami");
+const ORG_PATH: &str = lp_path!("/organization/info");
+const ROLE_PATH: &str = lp_path!("/roles/");
+const ENROLLMENT_PATH: &str = lp_path!("/enrollments/");
+const ATTRIBUTE_PATH: &str = lp_path!("/attributes/users/");
 
 #[derive(Debug)]
 pub struct Sync {
-    pub app_id: &'static str,
-    pub app_key: &'static [u8],
-    pub usr_id: &'static str,
-    pub usr_key: &'static [u8],
-    pub uri_base: &'static str,
+    pub auth: Box<dyn Authenticator>,
+    pub uri_base: String,
     pub client: Client,
-}
-
-fn signature(key: &[u8], message: &[u8]) -> String {
-    let mut mac = HmacSha256::new_varkey(key).unwrap();
-    mac.input(message);
-    base64::encode_config(&mac.result().code(), URL_SAFE_NO_PAD)
+    pub throttle: Throttle,
+    /// Org unit id of the organization root, discovered when None.
+    pub org_root: Mutex<Option<usize>>,
+    /// Report the intended changes instead of writing them to d2l.
+    pub dry_run: bool,
+    /// Compare the org-level role of existing users, which costs a request for each.
+    pub role_sync: bool,
 }
 
 impl Sync {
-    pub fn upsert(&self, role: Role, user_base: &UserBase) -> Result<SyncOk, SyncError> {
-        match self.read(&user_base)? {
-            Some(user) => if user.user_base == *user_base && user.activation.is_active == true {
-                Ok(SyncOk::NOP)
-            } else {
-                self.update(user.user_id, &user_base)
+    pub fn upsert(&self, role: &Role, user_base: &UserBase) -> Result<SyncOk, SyncError> {
+        self.upsert_id(role, user_base).map(|(ok, _)| ok)
+    }
+
+    /// Upsert the user along with the UserId of their account,
+    /// which is only unknown when a dry run would have created it.
+    pub fn upsert_id(&self, role: &Role, user_base: &UserBase) -> Result<(SyncOk, Option<usize>), SyncError> {
+        match self.read(&user_base.user_name)? {
+            Some(user) => Ok((self.converge(role, user_base, Some(&user))?, Some(user.user_id))),
+            None => Ok((self.outcome(SyncOk::Created), self.create(role, user_base)?)),
+        }
+    }
+
+    /// Bring the d2l account in line with the user_base given
+    /// the current state of the account as already read from d2l.
+    pub fn converge(&self, role: &Role, user_base: &UserBase, user: Option<&UserReadOrUpdate>) -> Result<SyncOk, SyncError> {
+        match user {
+            Some(user) => {
+                let mut changes = user.diff(user_base);
+                let attributes = self.attribute_changes(user.user_id, user_base)?;
+                let role_change = if self.role_sync { self.role_change(user.user_id, role)? } else { None };
+                if changes.is_empty() && attributes.is_empty() && role_change.is_none() {
+                    return Ok(SyncOk::NOP);
+                }
+                if !changes.is_empty() {
+                    self.put(user.user_id, user_base, true)?;
+                }
+                if !attributes.is_empty() {
+                    self.put_attributes(user.user_id, user_base)?;
+                }
+                changes.extend(attributes);
+                match role_change {
+                    None => Ok(self.outcome(SyncOk::Updated(changes))),
+                    Some(change) => {
+                        // Enrolling at the org root again replaces the org-level role.
+                        self.enroll(self.org_root()?, user.user_id, role)?;
+                        let enrolled = change.old.is_none();
+                        changes.push(change);
+                        if enrolled {
+                            log::warn("No org-level enrollment, enrolling at the org root", Fields {
+                                user_id: Some(user.user_id),
+                                user_name: Some(&user_base.user_name),
+                                ..Fields::default()
+                            });
+                            Ok(self.outcome(SyncOk::OrgEnrolled(changes)))
+                        } else {
+                            Ok(self.outcome(SyncOk::RoleChanged(changes)))
+                        }
+                    },
+                }
             },
-            None => self.create(role, &user_base),
+            None => self.create(role, user_base).map(|_| self.outcome(SyncOk::Created)),
         }
     }
 
-    pub fn read(&self, user_base: &UserBase) -> Result<Option<(UserReadOrUpdate)>, SyncError> {
-        let epoch = Utc::now().timestamp();
-        let sig_body = format!("{}&{}&{}", "GET", USR_PATH, epoch);
+    // Mark the outcome of a change which a dry run only reports.
+    fn outcome(&self, ok: SyncOk) -> SyncOk {
+        if self.dry_run {
+            SyncOk::DryRun(Box::new(ok))
+        } else {
+            ok
+        }
+    }
+
+    /// Apply the deprovision policy to the d2l account of a user
+    /// that no longer exists within the source.
+    pub fn deprovision(&self, policy: Policy, user_name: &str) -> Result<SyncOk, SyncError> {
+        match policy {
+            Policy::Ignore => Ok(SyncOk::NOP),
+            _ => match self.read(user_name)? {
+                Some(user) => self.deprovision_user(policy, &user),
+                None => Ok(SyncOk::NOP),
+            },
+        }
+    }
+
+    pub fn deprovision_user(&self, policy: Policy, user: &UserReadOrUpdate) -> Result<SyncOk, SyncError> {
+        if !policy.applies(user) {
+            return Ok(SyncOk::NOP);
+        }
+        match policy {
+            Policy::Deactivate => self.deactivate(user.user_id, &user.user_base),
+            _ => self.delete(user.user_id),
+        }
+    }
 
-        let app_sig = signature(self.app_key, sig_body.as_bytes());
-        let usr_sig = signature(self.usr_key, sig_body.as_bytes());
+    // Send an authenticated request, waiting for the rate limit and retrying
+    // whenever d2l responds that it is too busy to handle the request.
+    fn send(&self, method: Method, path: &str, query: &str, body: Option<String>) -> Result<Response, SyncError> {
+        let mut attempt = 1;
+        let mut recovered = false;
+        loop {
+            self.throttle.acquire();
+            let mut request = self.auth.request(&self.client, method.clone(), &self.uri_base, path, query)?;
+            if let Some(ref body) = body {
+                request = request.body(body.clone());
+            }
+            let start = Instant::now();
+            let result = request.send();
+            let status = result.as_ref().ok().map(Response::status);
+            METRICS.request(&method, path, status, start.elapsed());
+            log::debug("Request", Fields {
+                method: Some(method.as_str()),
+                path: Some(path),
+                status: status.map(|s| s.as_u16()),
+                duration: Some(start.elapsed()),
+                ..Fields::default()
+            });
+            let mut resp = result?;
+            // Give the authenticator a single chance to recover from a
+            // rejected request such as a skewed clock or expired token.
+            if resp.status() == StatusCode::UNAUTHORIZED || resp.status() == StatusCode::FORBIDDEN {
+                let mut body = String::new();
+                resp.read_to_string(&mut body)?;
+                if !recovered && self.auth.recover(&self.client, resp.status(), &body)? {
+                    recovered = true;
+                    continue;
+                }
+                return Err(SyncError::StatusCode(resp.status()));
+            }
+            match self.throttle.backoff(resp.status(), resp.headers(), attempt) {
+                Some(wait) => {
+                    log::warn(&format!("Throttled, retrying in {:?}", wait), Fields {
+                        method: Some(method.as_str()),
+                        path: Some(path),
+                        status: Some(resp.status().as_u16()),
+                        ..Fields::default()
+                    });
+                    sleep(wait);
+                    attempt += 1;
+                },
+                None => return Ok(resp),
+            }
+        }
+    }
 
-        let uri = format!("{}{}?{}{}&x_a={}&x_c={}&x_b={}&x_d={}&x_t={}", self.uri_base, USR_PATH, USR_QUERY, user_base.user_name, self.app_id, app_sig, self.usr_id, usr_sig, epoch);
-        let mut resp = self.client.get(&uri).send()?;
+    pub fn read(&self, user_name: &str) -> Result<Option<UserReadOrUpdate>, SyncError> {
+        let mut resp = self.send(Method::GET, USR_PATH, &format!("{}{}", USR_QUERY, user_name), None)?;
         if resp.status() == StatusCode::OK {
             let mut body = String::new();
             resp.read_to_string(&mut body)?;
@@ -62,30 +194,176 @@ impl Sync {
         }
     }
 
-    pub fn update(&self, user_id: usize, user_base: &UserBase) -> Result<SyncOk, SyncError> {
-        let user = UserReadOrUpdate {
-            user_base: user_base.clone(),
-            user_id: user_id,
-            activation: Activation{is_active: true},
+    /// Page through every user account within d2l.
+    pub fn users(&self) -> Result<Vec<UserReadOrUpdate>, SyncError> {
+        let mut users = Vec::new();
+        let mut bookmark: Option<String> = None;
+        loop {
+            let query = match bookmark {
+                Some(ref bookmark) => format!("{}{}", BOOKMARK_QUERY, bookmark),
+                None => String::new(),
+            };
+            let mut resp = self.send(Method::GET, USR_PATH, &query, None)?;
+            if resp.status() != StatusCode::OK {
+                return Err(SyncError::StatusCode(resp.status()));
+            }
+            let mut body = String::new();
+            resp.read_to_string(&mut body)?;
+            let page: PagedResultSet<UserReadOrUpdate> = serde_json::from_str(&body)?;
+            users.extend(page.items);
+            if !page.paging_info.has_more_items {
+                return Ok(users);
+            }
+            bookmark = page.paging_info.bookmark;
+        }
+    }
+
+    /// The account requests are made as, confirming d2l is reachable. As this
+    /// serves the readiness probe it bypasses the throttle and request metrics.
+    pub fn whoami(&self) -> Result<WhoAmI, SyncError> {
+        let mut resp = self.auth.request(&self.client, Method::GET, &self.uri_base, WHOAMI_PATH, "")?.send()?;
+        if resp.status() != StatusCode::OK {
+            return Err(SyncError::StatusCode(resp.status()));
+        }
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// The org unit id of the organization root, discovered
+    /// from d2l unless configured.
+    pub fn org_root(&self) -> Result<usize, SyncError> {
+        let mut org_root = self.org_root.lock().unwrap();
+        if let Some(id) = *org_root {
+            return Ok(id);
+        }
+        let mut resp = self.send(Method::GET, ORG_PATH, "", None)?;
+        if resp.status() != StatusCode::OK {
+            return Err(SyncError::StatusCode(resp.status()));
+        }
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        let info: OrgInfo = serde_json::from_str(&body)?;
+        let id = info.identifier.parse::<usize>().map_err(|_| SyncError::Parse(format!("Invalid org unit id {:?}", info.identifier)))?;
+        *org_root = Some(id);
+        Ok(id)
+    }
+
+    /// The user's role id from their enrollment at the org root.
+    pub fn org_role(&self, user_id: usize) -> Result<Option<usize>, SyncError> {
+        self.enrollment_role(self.org_root()?, user_id)
+    }
+
+    /// The user's role id from their enrollment within the org unit.
+    pub fn enrollment_role(&self, org_unit_id: usize, user_id: usize) -> Result<Option<usize>, SyncError> {
+        let path = format!("{}orgUnits/{}/users/{}", ENROLLMENT_PATH, org_unit_id, user_id);
+        let mut resp = self.send(Method::GET, &path, "", None)?;
+        if resp.status() == StatusCode::OK {
+            let mut body = String::new();
+            resp.read_to_string(&mut body)?;
+            let enrollment: Enrollment = serde_json::from_str(&body)?;
+            Ok(Some(enrollment.role_id))
+        } else if resp.status() == StatusCode::NOT_FOUND {
+            Ok(None)
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
+
+    // Compare the user's org-level role with the desired role.
+    fn role_change(&self, user_id: usize, role: &Role) -> Result<Option<Change>, SyncError> {
+        let current = self.org_role(user_id)?.map(|id| id.to_string());
+        if current.as_deref() == Some(role.id()) {
+            Ok(None)
+        } else {
+            Ok(Some(Change {
+                field: "RoleId".to_string(),
+                old: current,
+                new: Some(role.id().to_string()),
+            }))
+        }
+    }
+
+    /// List the roles defined within d2l.
+    pub fn roles(&self) -> Result<Vec<RoleData>, SyncError> {
+        let mut resp = self.send(Method::GET, ROLE_PATH, "", None)?;
+        if resp.status() == StatusCode::OK {
+            let mut body = String::new();
+            resp.read_to_string(&mut body)?;
+            Ok(serde_json::from_str(&body)?)
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
+
+    // Compare the user's attributes with those provided by the source.
+    fn attribute_changes(&self, user_id: usize, user_base: &UserBase) -> Result<Vec<Change>, SyncError> {
+        if user_base.attributes.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(self.attributes(user_id)?.diff(&user_base.attributes))
+    }
+
+    /// The org-defined attributes of a user.
+    pub fn attributes(&self, user_id: usize) -> Result<UserAttributes, SyncError> {
+        let mut resp = self.send(Method::GET, &format!("{}{}", ATTRIBUTE_PATH, user_id), "", None)?;
+        if resp.status() == StatusCode::OK {
+            let mut body = String::new();
+            resp.read_to_string(&mut body)?;
+            Ok(serde_json::from_str(&body)?)
+        } else if resp.status() == StatusCode::NOT_FOUND {
+            Ok(UserAttributes { user_id, attributes: Vec::new() })
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
+
+    // Write the attributes provided by the source; attributes
+    // not provided by the source are left untouched by d2l.
+    fn put_attributes(&self, user_id: usize, user_base: &UserBase) -> Result<(), SyncError> {
+        let attributes = UserAttributes {
+            user_id,
+            attributes: user_base.attributes.iter().map(|(id, value)| Attribute {
+                attribute_id: id.clone(),
+                value: vec![value.clone()],
+            }).collect(),
         };
-        let epoch = Utc::now().timestamp();
-        let sig_body = format!("{}&{}{}&{}", "PUT", USR_PATH, user_id, epoch);
+        if self.dry_run {
+            return Ok(());
+        }
+        let resp = self.send(Method::PUT, &format!("{}{}", ATTRIBUTE_PATH, user_id), "", Some(serde_json::to_string(&attributes)?))?;
+        if resp.status() == StatusCode::OK {
+            Ok(())
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
 
-        let app_sig = signature(self.app_key, sig_body.as_bytes());
-        let usr_sig = signature(self.usr_key, sig_body.as_bytes());
+    pub fn deactivate(&self, user_id: usize, user_base: &UserBase) -> Result<SyncOk, SyncError> {
+        self.put(user_id, user_base, false)?;
+        Ok(self.outcome(SyncOk::Deactivated))
+    }
 
-        let uri = format!("{}{}{}?&x_a={}&x_c={}&x_b={}&x_d={}&x_t={}", self.uri_base, USR_PATH, user_id, self.app_id, app_sig, self.usr_id, usr_sig, epoch);
-        let resp = self.client.put(&uri)
-            .body(serde_json::to_string(&user)?)
-            .send()?;
+    fn put(&self, user_id: usize, user_base: &UserBase, is_active: bool) -> Result<(), SyncError> {
+        let user = UserReadOrUpdate {
+            user_base: user_base.clone(),
+            user_id,
+            activation: Activation{is_active},
+        };
+        if self.dry_run {
+            return Ok(());
+        }
+        let resp = self.send(Method::PUT, &format!("{}{}", USR_PATH, user_id), "", Some(serde_json::to_string(&user)?))?;
         if resp.status() == StatusCode::OK {
-            Ok(SyncOk::Updated)
+            Ok(())
         } else {
             Err(SyncError::StatusCode(resp.status()))
         }
     }
 
-    pub fn create(&self, role: Role, user_base: &UserBase) -> Result<SyncOk, SyncError> {
+    /// Create the account, returning the UserId d2l assigned
+    /// to it which is unknown on a dry run.
+    pub fn create(&self, role: &Role, user_base: &UserBase) -> Result<Option<usize>, SyncError> {
         let user = UserCreate {
             user_base: user_base.clone(),
             role_id: role.id().to_string(),
@@ -93,18 +371,80 @@ impl Sync {
             send_creation_email: false,
         };
 
-        let epoch = Utc::now().timestamp();
-        let sig_body = format!("{}&{}&{}", "POST", USR_PATH, epoch);
+        if self.dry_run {
+            log::info(&format!("DryRun: Create as {}", role.name()), Fields {
+                user_name: Some(&user_base.user_name),
+                action: Some("create"),
+                ..Fields::default()
+            });
+            return Ok(None);
+        }
+        let mut resp = self.send(Method::POST, USR_PATH, "", Some(serde_json::to_string(&user)?))?;
+        if resp.status() != StatusCode::OK {
+            return Err(SyncError::StatusCode(resp.status()));
+        }
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        let created: UserReadOrUpdate = serde_json::from_str(&body)?;
+        // Attributes may only be set once d2l has assigned the UserId
+        if !user_base.attributes.is_empty() {
+            self.put_attributes(created.user_id, user_base)?;
+        }
+        Ok(Some(created.user_id))
+    }
+
+    /// Enroll the user within the org unit, or update their role when already enrolled.
+    pub fn enroll(&self, org_unit_id: usize, user_id: usize, role: &Role) -> Result<SyncOk, SyncError> {
+        let enrollment = Enrollment {
+            org_unit_id,
+            user_id,
+            role_id: role.id().parse().map_err(|_| SyncError::Parse(format!("Invalid role id {:?}", role.id())))?,
+        };
+        if self.dry_run {
+            return Ok(self.outcome(SyncOk::Enrolled(org_unit_id)));
+        }
+        let resp = self.send(Method::POST, ENROLLMENT_PATH, "", Some(serde_json::to_string(&enrollment)?))?;
+        if resp.status() == StatusCode::OK {
+            Ok(SyncOk::Enrolled(org_unit_id))
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
+
+    /// Enroll or drop the user within the org unit only when
+    /// their current enrollment differs from the desired one.
+    pub fn converge_enrollment(&self, org_unit_id: usize, user_id: usize, role: &Role, enrolled: bool) -> Result<SyncOk, SyncError> {
+        let current = self.enrollment_role(org_unit_id, user_id)?.map(|id| id.to_string());
+        match (enrolled, current) {
+            (true, Some(ref id)) if id == role.id() => Ok(SyncOk::NOP),
+            (true, _) => self.enroll(org_unit_id, user_id, role),
+            (false, Some(_)) => self.unenroll(org_unit_id, user_id),
+            (false, None) => Ok(SyncOk::NOP),
+        }
+    }
 
-        let app_sig = signature(self.app_key, sig_body.as_bytes());
-        let usr_sig = signature(self.usr_key, sig_body.as_bytes());
+    pub fn unenroll(&self, org_unit_id: usize, user_id: usize) -> Result<SyncOk, SyncError> {
+        if self.dry_run {
+            return Ok(self.outcome(SyncOk::Unenrolled(org_unit_id)));
+        }
+        let path = format!("{}orgUnits/{}/users/{}", ENROLLMENT_PATH, org_unit_id, user_id);
+        let resp = self.send(Method::DELETE, &path, "", None)?;
+        if resp.status() == StatusCode::OK {
+            Ok(SyncOk::Unenrolled(org_unit_id))
+        } else if resp.status() == StatusCode::NOT_FOUND {
+            Ok(SyncOk::NOP)
+        } else {
+            Err(SyncError::StatusCode(resp.status()))
+        }
+    }
 
-        let uri = format!("{}{}?&x_a={}&x_c={}&x_b={}&x_d={}&x_t={}", self.uri_base, USR_PATH, self.app_id, app_sig, self.usr_id, usr_sig, epoch);
-        let resp = self.client.post(&uri)
-            .body(serde_json::to_string(&user)?)
-            .send()?;
+    pub fn delete(&self, user_id: usize) -> Result<SyncOk, SyncError> {
+        if self.dry_run {
+            return Ok(self.outcome(SyncOk::Deleted));
+        }
+        let resp = self.send(Method::DELETE, &format!("{}{}", USR_PATH, user_id), "", None)?;
         if resp.status() == StatusCode::OK {
-            Ok(SyncOk::Created)
+            Ok(SyncOk::Deleted)
         } else {
             Err(SyncError::StatusCode(resp.status()))
         }
@@ -113,17 +453,177 @@ impl Sync {
 
 #[derive(Debug)]
 pub enum SyncOk {
-    Updated,
+    /// Fields which were changed, including activation.
+    Updated(Vec<Change>),
     Created,
+    Deactivated,
+    Deleted,
+    /// Org-level role changed along with any other changed fields.
+    RoleChanged(Vec<Change>),
+    /// Enrolled at the org root as the user had no org-level role.
+    OrgEnrolled(Vec<Change>),
+    /// Org unit the user was enrolled within.
+    Enrolled(usize),
+    /// Org unit the user was dropped from.
+    Unenrolled(usize),
     NOP,
+    /// Outcome a dry run would have had, nothing was written to d2l.
+    DryRun(Box<SyncOk>),
+}
+
+impl fmt::Display for SyncOk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncOk::Updated(changes) => {
+                write!(f, "Updated")?;
+                for (i, change) in changes.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { ": " } else { "; " }, change)?;
+                }
+                Ok(())
+            },
+            SyncOk::RoleChanged(changes) => {
+                write!(f, "RoleChanged")?;
+                for (i, change) in changes.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { ": " } else { "; " }, change)?;
+                }
+                Ok(())
+            },
+            SyncOk::OrgEnrolled(changes) => {
+                write!(f, "OrgEnrolled")?;
+                for (i, change) in changes.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { ": " } else { "; " }, change)?;
+                }
+                Ok(())
+            },
+            SyncOk::Created => write!(f, "Created"),
+            SyncOk::Deactivated => write!(f, "Deactivated"),
+            SyncOk::Deleted => write!(f, "Deleted"),
+            SyncOk::Enrolled(org_unit_id) => write!(f, "Enrolled in {}", org_unit_id),
+            SyncOk::Unenrolled(org_unit_id) => write!(f, "Unenrolled from {}", org_unit_id),
+            SyncOk::NOP => write!(f, "NOP"),
+            SyncOk::DryRun(ok) => write!(f, "DryRun: {}", ok),
+        }
+    }
+}
+
+impl SyncOk {
+    /// Name of the outcome as reported by the metrics.
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            SyncOk::Updated(_) => "updated",
+            SyncOk::Created => "created",
+            SyncOk::Deactivated => "deactivated",
+            SyncOk::Deleted => "deleted",
+            SyncOk::RoleChanged(_) => "role_changed",
+            SyncOk::OrgEnrolled(_) => "org_enrolled",
+            SyncOk::Enrolled(_) => "enrolled",
+            SyncOk::Unenrolled(_) => "unenrolled",
+            SyncOk::NOP => "nop",
+            SyncOk::DryRun(_) => "dry_run",
+        }
+    }
+}
+
+/// Count of each outcome for runs which sync many users.
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub created: usize,
+    pub updated: usize,
+    pub role_changed: usize,
+    pub org_enrolled: usize,
+    pub deactivated: usize,
+    pub deleted: usize,
+    pub enrolled: usize,
+    pub unenrolled: usize,
+    pub nop: usize,
+    pub errors: usize,
+}
+
+impl Tally {
+    pub fn add(&mut self, result: &Result<SyncOk, SyncError>) {
+        match result {
+            Ok(ok) => self.count(ok),
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    // A dry run is counted by the outcome it would have had.
+    fn count(&mut self, ok: &SyncOk) {
+        match ok {
+            SyncOk::Created => self.created += 1,
+            SyncOk::Updated(_) => self.updated += 1,
+            SyncOk::RoleChanged(_) => self.role_changed += 1,
+            SyncOk::OrgEnrolled(_) => self.org_enrolled += 1,
+            SyncOk::Deactivated => self.deactivated += 1,
+            SyncOk::Deleted => self.deleted += 1,
+            SyncOk::Enrolled(_) => self.enrolled += 1,
+            SyncOk::Unenrolled(_) => self.unenrolled += 1,
+            SyncOk::NOP => self.nop += 1,
+            SyncOk::DryRun(ok) => self.count(ok),
+        }
+    }
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Created: {}, Updated: {}, RoleChanged: {}, OrgEnrolled: {}, Deactivated: {}, Deleted: {}, Enrolled: {}, Unenrolled: {}, NOP: {}, Errors: {}",
+            self.created, self.updated, self.role_changed, self.org_enrolled, self.deactivated, self.deleted, self.enrolled, self.unenrolled, self.nop, self.errors)
+    }
 }
 
-#[derive(Debug)]
 pub enum SyncError {
     Http(reqwest::Error),
     StatusCode(StatusCode),
     Json(serde_json::Error),
     IO(std::io::Error),
+    /// A value d2l returned or was given which could not be interpreted.
+    Parse(String),
+}
+
+impl SyncError {
+    /// Errors which may succeed if the request is simply tried again
+    /// such as timeouts, dropped connections, and server side failures.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SyncError::Http(_) => true,
+            SyncError::StatusCode(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// Name of the variant as reported by the metrics.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            SyncError::Http(_) => "Http",
+            SyncError::StatusCode(_) => "StatusCode",
+            SyncError::Json(_) => "Json",
+            SyncError::IO(_) => "IO",
+            SyncError::Parse(_) => "Parse",
+        }
+    }
+
+    /// The status code d2l responded with, if any.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            SyncError::StatusCode(status) => Some(*status),
+            SyncError::Http(err) => err.status(),
+            _ => None,
+        }
+    }
+}
+
+// An Http error holds the request uri which carries the signatures
+// of ID/key authentication so its credentials are redacted.
+impl fmt::Debug for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Http(err) => write!(f, "Http({})", redact::secrets(&format!("{:?}", err))),
+            SyncError::StatusCode(status) => write!(f, "StatusCode({:?})", status),
+            SyncError::Json(err) => write!(f, "Json({:?})", err),
+            SyncError::IO(err) => write!(f, "IO({:?})", err),
+            SyncError::Parse(msg) => write!(f, "Parse({:?})", msg),
+        }
+    }
 }
 
 impl From<reqwest::Error> for SyncError {
@@ -143,3 +643,186 @@ impl From<std::io::Error> for SyncError {
         SyncError::IO(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::IdKey;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicI64;
+    use std::thread;
+
+    // A stand-in for d2l answering each request with the status and body of
+    // the first route matching its method and path, or a 404 otherwise, and
+    // recording every request it received.
+    fn d2l(routes: Vec<(&'static str, String, u16, &'static str)>) -> (Sync, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri_base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut length = 0;
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                if header.to_lowercase().starts_with("content-length:") {
+                    length = header[15..].trim().parse().unwrap();
+                }
+                header.clear();
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default().to_string();
+            let (status, body) = routes.iter()
+                .find(|r| r.0 == method && r.1 == path)
+                .map_or((404, ""), |r| (r.2, r.3));
+            received.lock().unwrap().push(format!("{} {}", method, path));
+            write!(stream, "HTTP/1.1 {} D2L\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+        });
+        let sync = Sync {
+            auth: Box::new(IdKey {
+                app_id: "app".to_string(),
+                app_key: b"app".to_vec(),
+                usr_id: "usr".to_string(),
+                usr_key: b"usr".to_vec(),
+                skew: AtomicI64::new(0),
+            }),
+            uri_base,
+            client: Client::new(),
+            throttle: Throttle::new(None, 0),
+            org_root: Mutex::new(Some(6606)),
+            dry_run: false,
+            role_sync: true,
+        };
+        (sync, requests)
+    }
+
+    fn enrollment(org_unit_id: usize) -> String {
+        format!("{}orgUnits/{}/users/100", ENROLLMENT_PATH, org_unit_id)
+    }
+
+    #[test]
+    fn test_converge_enrollment() {
+        let student: Role = "Student".parse().unwrap();
+        let (sync, requests) = d2l(vec![
+            ("GET", enrollment(1), 200, r#"{"OrgUnitId":1,"UserId":100,"RoleId":110,"IsCascading":false}"#),
+            ("GET", enrollment(2), 200, r#"{"OrgUnitId":2,"UserId":100,"RoleId":109,"IsCascading":false}"#),
+            ("POST", ENROLLMENT_PATH.to_string(), 200, r#"{}"#),
+            ("DELETE", enrollment(2), 200, r#"{}"#),
+        ]);
+
+        // Already enrolled with the role, or already dropped, needs no write
+        assert_eq!("NOP", sync.converge_enrollment(1, 100, &student, true).unwrap().to_string());
+        assert_eq!("NOP", sync.converge_enrollment(3, 100, &student, false).unwrap().to_string());
+        // Enrolled with another role, or not at all, is enrolled with the role
+        assert_eq!("Enrolled in 2", sync.converge_enrollment(2, 100, &student, true).unwrap().to_string());
+        assert_eq!("Enrolled in 3", sync.converge_enrollment(3, 100, &student, true).unwrap().to_string());
+        // Only dropped while still enrolled
+        assert_eq!("Unenrolled from 2", sync.converge_enrollment(2, 100, &student, false).unwrap().to_string());
+
+        let get = |id| format!("GET {}", enrollment(id));
+        let expected = vec![
+            get(1),
+            get(3),
+            get(2), format!("POST {}", ENROLLMENT_PATH),
+            get(3), format!("POST {}", ENROLLMENT_PATH),
+            get(2), format!("DELETE {}", enrollment(2)),
+        ];
+        assert_eq!(expected, *requests.lock().unwrap());
+    }
+
+    #[test]
+    fn test_converge_role_changed() {
+        let student: Role = "Student".parse().unwrap();
+        let (sync, requests) = d2l(vec![
+            ("GET", enrollment(6606), 200, r#"{"OrgUnitId":6606,"UserId":100,"RoleId":109,"IsCascading":false}"#),
+            ("POST", ENROLLMENT_PATH.to_string(), 200, r#"{}"#),
+        ]);
+        let user: UserReadOrUpdate = serde_json::from_str(r#"{"FirstName":"John","MiddleName":null,"LastName":"Doe","UserName":"j_d1","OrgDefinedId":null,"ExternalEmail":null,"OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#).unwrap();
+
+        // Only the org-level role differs so the user data is left alone
+        let result = sync.converge(&student, &user.user_base, Some(&user));
+        assert_eq!(r#"RoleChanged: RoleId changed from "109" to "110""#, result.as_ref().unwrap().to_string());
+        assert_eq!("role_changed", result.as_ref().unwrap().outcome());
+        let expected = vec![format!("GET {}", enrollment(6606)), format!("POST {}", ENROLLMENT_PATH)];
+        assert_eq!(expected, *requests.lock().unwrap());
+    }
+
+    #[test]
+    fn test_converge_org_enrolled() {
+        let student: Role = "Student".parse().unwrap();
+        let (mut sync, requests) = d2l(vec![
+            ("POST", ENROLLMENT_PATH.to_string(), 200, r#"{}"#),
+        ]);
+        let user: UserReadOrUpdate = serde_json::from_str(r#"{"FirstName":"John","MiddleName":null,"LastName":"Doe","UserName":"j_d1","OrgDefinedId":null,"ExternalEmail":null,"OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#).unwrap();
+
+        // Without an org-level enrollment the user is enrolled at the org root
+        let result = sync.converge(&student, &user.user_base, Some(&user));
+        assert_eq!(r#"OrgEnrolled: RoleId changed from null to "110""#, result.as_ref().unwrap().to_string());
+        assert_eq!("org_enrolled", result.as_ref().unwrap().outcome());
+        let expected = vec![format!("GET {}", enrollment(6606)), format!("POST {}", ENROLLMENT_PATH)];
+        assert_eq!(expected, *requests.lock().unwrap());
+
+        // The role is left alone, without a request, when it is not synced
+        sync.role_sync = false;
+        assert_eq!("NOP", sync.converge(&student, &user.user_base, Some(&user)).unwrap().to_string());
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_org_root() {
+        let (sync, _) = d2l(vec![
+            ("GET", ORG_PATH.to_string(), 200, r#"{"Identifier":"root","Name":"Texas State","Path":"/"}"#),
+        ]);
+        *sync.org_root.lock().unwrap() = None;
+        match sync.org_root() {
+            Err(SyncError::Parse(msg)) => assert_eq!(r#"Invalid org unit id "root""#, msg),
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let student: Role = "Student".parse().unwrap();
+        let (mut sync, requests) = d2l(vec![
+            ("GET", enrollment(6606), 200, r#"{"OrgUnitId":6606,"UserId":100,"RoleId":110,"IsCascading":false}"#),
+        ]);
+        sync.dry_run = true;
+        let user: UserReadOrUpdate = serde_json::from_str(r#"{"FirstName":"John","MiddleName":null,"LastName":"Doe","UserName":"j_d1","OrgDefinedId":null,"ExternalEmail":null,"OrgId":6606,"UserId":100,"Activation":{"IsActive":true}}"#).unwrap();
+        let desired = UserBase { last_name: "Smith".to_string(), ..user.user_base.clone() };
+
+        let result = sync.converge(&student, &desired, Some(&user));
+        assert_eq!(r#"DryRun: Updated: LastName changed from "Doe" to "Smith""#, result.as_ref().unwrap().to_string());
+        assert_eq!("dry_run", result.as_ref().unwrap().outcome());
+        let mut tally = Tally::default();
+        tally.add(&result);
+        assert_eq!(1, tally.updated);
+        assert_eq!("DryRun: Unenrolled from 2", sync.unenroll(2, 100).unwrap().to_string());
+        // Only the reads were sent to d2l
+        assert_eq!(vec![format!("GET {}", enrollment(6606))], *requests.lock().unwrap());
+    }
+
+    #[test]
+    fn test_upsert_id() {
+        let student: Role = "Student".parse().unwrap();
+        let user_base = UserBase {
+            first_name: "John".to_string(),
+            last_name: "Doe".to_string(),
+            user_name: "j_d1".to_string(),
+            ..UserBase::default()
+        };
+        let (sync, requests) = d2l(vec![
+            ("POST", USR_PATH.to_string(), 200, r#"{"FirstName":"John","MiddleName":null,"LastName":"Doe","UserName":"j_d1","OrgDefinedId":null,"ExternalEmail":null,"OrgId":6606,"UserId":101,"Activation":{"IsActive":true}}"#),
+        ]);
+        let (update_type, user_id) = sync.upsert_id(&student, &user_base).unwrap();
+        assert_eq!("Created", update_type.to_string());
+        assert_eq!(Some(101), user_id);
+        assert_eq!(vec![format!("GET {}", USR_PATH), format!("POST {}", USR_PATH)], *requests.lock().unwrap());
+    }
+}
@@ -0,0 +1,190 @@
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use hyper::header::{HeaderMap, RETRY_AFTER};
+
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use metrics::METRICS;
+
+// Longest wait honored from Retry-After, the same bound as the retry delay,
+// so a misconfigured or hostile header cannot stall the sync for hours.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// Client side token bucket limiting requests to rate per
+/// second while allowing short bursts of up to burst requests.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    // Take a token, returning how long we had to wait for one. When none
+    // is left the token is borrowed against the refill so the lock is
+    // released while waiting and each caller waits its turn.
+    fn acquire(&self) -> Option<Duration> {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.1);
+            state.0 = (state.0 + elapsed.as_secs_f64() * self.rate).min(self.burst) - 1.0;
+            state.1 = now;
+            if state.0 >= 0.0 {
+                return None;
+            }
+            Duration::from_secs_f64(-state.0 / self.rate)
+        };
+        sleep(wait);
+        Some(wait)
+    }
+}
+
+/// Number of times requests were held back either by the
+/// client side rate limit or by d2l responding 429 or 503.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrottleCounts {
+    pub rate_limited: usize,
+    pub too_many_requests: usize,
+    pub service_unavailable: usize,
+}
+
+impl fmt::Display for ThrottleCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RateLimited: {}, TooManyRequests: {}, ServiceUnavailable: {}",
+            self.rate_limited, self.too_many_requests, self.service_unavailable)
+    }
+}
+
+#[derive(Debug)]
+pub struct Throttle {
+    pub bucket: Option<TokenBucket>,
+    /// Number of times a request is retried after a 429 or 503.
+    pub retries: u32,
+    rate_limited: AtomicUsize,
+    too_many_requests: AtomicUsize,
+    service_unavailable: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn new(bucket: Option<TokenBucket>, retries: u32) -> Throttle {
+        Throttle {
+            bucket,
+            retries,
+            rate_limited: AtomicUsize::new(0),
+            too_many_requests: AtomicUsize::new(0),
+            service_unavailable: AtomicUsize::new(0),
+        }
+    }
+
+    /// Block until the rate limit allows another request.
+    pub fn acquire(&self) {
+        if let Some(ref bucket) = self.bucket {
+            if bucket.acquire().is_some() {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                METRICS.throttled("rate_limited");
+            }
+        }
+    }
+
+    /// When d2l has asked us to back off returns how long to wait before
+    /// retrying, honoring the Retry-After header up to MAX_RETRY_AFTER.
+    pub fn backoff(&self, status: StatusCode, headers: &HeaderMap, attempt: u32) -> Option<Duration> {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => {
+                self.too_many_requests.fetch_add(1, Ordering::Relaxed);
+                METRICS.throttled("too_many_requests");
+            },
+            StatusCode::SERVICE_UNAVAILABLE => {
+                self.service_unavailable.fetch_add(1, Ordering::Relaxed);
+                METRICS.throttled("service_unavailable");
+            },
+            _ => return None,
+        };
+        if attempt > self.retries {
+            return None;
+        }
+        let wait = retry_after(headers, Utc::now()).unwrap_or_else(|| Duration::from_secs(1 << attempt.min(6)));
+        Some(wait.min(MAX_RETRY_AFTER))
+    }
+
+    pub fn counts(&self) -> ThrottleCounts {
+        ThrottleCounts {
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            too_many_requests: self.too_many_requests.load(Ordering::Relaxed),
+            service_unavailable: self.service_unavailable.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or_else(|_| Duration::from_secs(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_retry_after() {
+        let now = Utc.ymd(2015, 10, 21).and_hms(7, 27, 30);
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, retry_after(&headers, now));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(&headers, now));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(Some(Duration::from_secs(30)), retry_after(&headers, now));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:27:00 GMT"));
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(&headers, now));
+    }
+
+    #[test]
+    fn test_throttle_backoff() {
+        let throttle = Throttle::new(None, 2);
+        let headers = HeaderMap::new();
+        assert_eq!(None, throttle.backoff(StatusCode::OK, &headers, 1));
+        assert_eq!(Some(Duration::from_secs(2)), throttle.backoff(StatusCode::TOO_MANY_REQUESTS, &headers, 1));
+        assert_eq!(Some(Duration::from_secs(4)), throttle.backoff(StatusCode::SERVICE_UNAVAILABLE, &headers, 2));
+        assert_eq!(None, throttle.backoff(StatusCode::SERVICE_UNAVAILABLE, &headers, 3));
+        assert_eq!(ThrottleCounts { rate_limited: 0, too_many_requests: 1, service_unavailable: 2 }, throttle.counts());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(Some(Duration::from_secs(30)), throttle.backoff(StatusCode::TOO_MANY_REQUESTS, &headers, 1));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("86400"));
+        assert_eq!(Some(MAX_RETRY_AFTER), throttle.backoff(StatusCode::TOO_MANY_REQUESTS, &headers, 1));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000.0, 2.0);
+        let start = Instant::now();
+        assert_eq!(None, bucket.acquire());
+        assert_eq!(None, bucket.acquire());
+        // Once the burst is spent each token takes a millisecond to refill,
+        // however long the scheduler held us up between acquires
+        bucket.acquire();
+        bucket.acquire();
+        assert!(start.elapsed() >= Duration::from_micros(1900));
+    }
+}